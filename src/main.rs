@@ -1,34 +1,148 @@
-#![feature(core_intrinsics)]
 #![feature(ip_bits)]
 #![feature(ascii_char)]
 #![feature(ascii_char_variants)]
 #![feature(allocator_api)]
 
-use std::io::stdin;
+use std::fs;
+use std::io::{self, stdin, BufReader, BufWriter, Write};
 use std::sync::{Arc, Condvar, Mutex};
 use std::thread;
+use std::time::{Duration, SystemTime};
 
-use clap::Parser;
-use scan::output::FileOut;
+use clap::{Parser, Subcommand};
+use rand::Rng;
+use scan::blocklist::Blocklist;
+use scan::checkpoint::{fingerprint, Checkpoint};
+use scan::cookie::CookieHasher;
+use scan::output::{
+    self as output, binary::BinaryReader, Field, OutputFormat, OutputWriter, ScanInfo,
+};
+use scan::probe::{tcp::TcpOptions, ProbeType};
+use scan::target::{expand_ports, IpRange, PortRange, TargetSet};
 
 #[derive(Parser, Debug)]
 #[command(name = "Sint")]
 #[command(version = "1.0")]
 #[command(about = "Scanner that scans !")]
+#[command(args_conflicts_with_subcommands = true)]
+#[command(
+    after_help = "Only the first reply of each target is reported. Later ones are dropped exactly \
+                  for scans of up to a billion targets, counting each port of an address as a target. \
+                  Larger scans use bloom filters whose false positives drop up to 0.1% of the results."
+)]
 struct Args {
-    /// Output file
+    #[command(subcommand)]
+    command: Option<Command>,
+    /// Output file, results go to stdout when omitted or -
     #[arg(short = 'o', long = "output")]
-    output: String,
-    #[arg(short = 'p', long = "port")]
-    /// Port to be scanned
-    port: u16,
+    output: Option<String>,
+    #[arg(long = "output-format", value_name = "FORMAT", value_enum, default_value_t = OutputFormat::Text)]
+    /// Format of the results
+    output_format: OutputFormat,
+    #[arg(long = "fields", value_delimiter = ',')]
+    /// Fields written for each result, defaults depend on the format
+    fields: Vec<Field>,
+    #[arg(short = 'p', long = "port", value_delimiter = ',')]
+    /// Ports to be scanned, e.g. 22,80,8000-8100, unused by icmp-echo
+    ports: Vec<PortRange>,
+    #[arg(short = 'P', long = "probe", value_enum, default_value_t = ProbeType::TcpSyn)]
+    /// Kind of probe sent to the targets
+    probe: ProbeType,
+    #[arg(long = "tcp-options", value_enum, default_value_t = TcpOptions::Linux)]
+    /// Stack whose options the SYN probes imitate
+    tcp_options: TcpOptions,
+    #[arg(long = "ttl", default_value_t = 64, value_parser = clap::value_parser!(u8).range(1..))]
+    /// Time to live of the probes
+    ttl: u8,
+    #[arg(long = "probes", default_value_t = 1, value_parser = clap::value_parser!(u32).range(1..))]
+    /// Probes sent to each target, for lossy links
+    probes: u32,
+    #[arg(long = "probe-delay", value_name = "MS", default_value_t = 1000)]
+    /// Milliseconds between two probes to the same target
+    probe_delay: u64,
     #[arg(short = 'i', long = "interface")]
     /// Interface to scan on
     interface: Option<String>,
+    #[arg(short = 't', long = "target", value_delimiter = ',')]
+    /// CIDR blocks, ranges or addresses to scan [default: 0.0.0.0/0]
+    targets: Vec<IpRange>,
+    #[arg(short = 'e', long = "exclude", value_delimiter = ',')]
+    /// Ranges to never scan, same syntax as --target
+    exclude: Vec<IpRange>,
+    #[arg(long = "exclude-file", value_name = "FILE")]
+    /// File with one range to exclude per line, `#` starts a comment
+    exclude_file: Option<String>,
+    #[arg(long = "no-default-blocklist")]
+    /// Also scan the IANA special-purpose and reserved ranges
+    no_default_blocklist: bool,
+    #[arg(short = 's', long = "seed")]
+    /// Seed of the permutation and cookies, random by default
+    seed: Option<u64>,
+    #[arg(long = "source-ports", value_name = "PORTS")]
+    /// Source port range of the probes, the kernel's ephemeral range by default
+    source_ports: Option<PortRange>,
+    #[arg(long = "shard", default_value_t = 0)]
+    /// Slice of the scan done by this host, from 0
+    shard: u32,
+    #[arg(long = "shards", default_value_t = 1, value_parser = clap::value_parser!(u32).range(1..))]
+    /// Number of hosts sharing the scan with the same seed
+    shards: u32,
+    #[arg(short = 'r', long = "rate", value_name = "PPS", value_parser = parse_si)]
+    /// Packets per second, accepts K, M and G suffixes
+    rate: Option<u64>,
+    #[arg(short = 'b', long = "bandwidth", value_name = "BPS", value_parser = parse_si)]
+    /// Bits per second, accepts K, M and G suffixes
+    bandwidth: Option<u64>,
+    #[arg(long = "report-closed")]
+    /// Also output the ports found closed, from RSTs or ICMP port unreachables
+    report_closed: bool,
+    #[arg(short = 'c', long = "cooldown", value_name = "SECS", default_value_t = 8)]
+    /// Seconds to keep receiving replies once everything is sent
+    cooldown: u64,
+    #[arg(long = "checkpoint", value_name = "FILE")]
+    /// File the progress is periodically saved to
+    checkpoint: Option<String>,
+    #[arg(long = "checkpoint-interval", value_name = "SECS", default_value_t = 60)]
+    /// Seconds between two checkpoints
+    checkpoint_interval: u64,
+    #[arg(long = "resume", value_name = "FILE", conflicts_with_all = ["seed", "source_ports", "shard", "shards"])]
+    /// Checkpoint of an interrupted scan to continue, results of its last seconds may show twice
+    resume: Option<String>,
+}
+
+#[derive(Subcommand, Debug)]
+enum Command {
+    /// Converts a binary output to another format on stdout
+    Read {
+        /// Binary output of a scan
+        file: String,
+        #[arg(long = "output-format", value_name = "FORMAT", value_enum, default_value_t = OutputFormat::Text)]
+        /// Format to convert to
+        output_format: OutputFormat,
+        #[arg(long = "fields", value_delimiter = ',')]
+        /// Fields written for each result, defaults depend on the format
+        fields: Vec<Field>,
+    },
 }
 
 mod scan;
 
+/// Parses a number with an optional K, M or G suffix
+fn parse_si(s: &str) -> Result<u64, String> {
+    let (number, multiplier) = match s.chars().last().map(|c| c.to_ascii_uppercase()) {
+        Some('K') => (&s[..s.len() - 1], 1_000),
+        Some('M') => (&s[..s.len() - 1], 1_000_000),
+        Some('G') => (&s[..s.len() - 1], 1_000_000_000),
+        _ => (s, 1),
+    };
+    number
+        .parse::<u64>()
+        .ok()
+        .and_then(|number| number.checked_mul(multiplier))
+        .filter(|number| *number > 0)
+        .ok_or_else(|| format!("Invalid number: {}", s))
+}
+
 pub struct RunState {
     pub paused: Mutex<bool>,
     pub cond: Condvar,
@@ -59,26 +173,189 @@ impl RunState {
 
 type SharedRunState = Arc<RunState>;
 
+fn fields_or_default(format: OutputFormat, fields: Vec<Field>) -> Vec<Field> {
+    if fields.is_empty() {
+        format.default_fields()
+    } else {
+        fields
+    }
+}
+
+/// Writes the results of the binary output at `path` to stdout in `format`
+fn read(path: &str, format: OutputFormat, fields: Vec<Field>) {
+    let file = fs::File::open(path).unwrap_or_else(|err| panic!("Failed opening file: {}", err));
+    let mut reader = BinaryReader::new(BufReader::new(file))
+        .unwrap_or_else(|err| panic!("Failed reading {}: {}", path, err));
+
+    let fields = fields_or_default(format, fields);
+    let mut output = format.build(BufWriter::new(io::stdout()), fields, reader.info.clone());
+    output.header().unwrap();
+    while let Some(result) = reader
+        .next_result()
+        .unwrap_or_else(|err| panic!("Failed reading {}: {}", path, err))
+    {
+        output.write(&result).unwrap();
+    }
+}
+
 fn main() {
     let args = Args::parse();
 
-    let interface_data;
-    match args.interface {
-        Some(interface) => {
-            interface_data = scan::network_data::InterfaceData::fetch_from_interface(&interface)
+    if let Some(Command::Read {
+        file,
+        output_format,
+        fields,
+    }) = args.command
+    {
+        read(&file, output_format, fields);
+        return;
+    }
+
+    let interface_data = match args.interface {
+        Some(interface) => scan::network_data::InterfaceData::fetch_from_interface(&interface),
+        None => scan::network_data::InterfaceData::fetch_default(),
+    };
+
+    let explicit_targets = !args.targets.is_empty();
+    let targets = if args.targets.is_empty() {
+        TargetSet::default()
+    } else {
+        TargetSet::new(args.targets)
+    };
+
+    let mut exclude = args.exclude;
+    if !args.no_default_blocklist {
+        exclude.extend(Blocklist::default_ranges());
+    }
+    if let Some(path) = args.exclude_file {
+        exclude.extend(Blocklist::parse_file(&path).unwrap_or_else(|err| panic!("{}", err)));
+    }
+    let blocklist = Blocklist::new(exclude);
+
+    assert!(
+        !targets.ranges().iter().all(|range| blocklist.covers(range)),
+        "Every target is excluded, by --exclude or by the default blocklist of private and reserved ranges"
+    );
+    if explicit_targets && !args.no_default_blocklist {
+        let default_blocklist = Blocklist::new(Blocklist::default_ranges());
+        if targets.ranges().iter().any(|range| default_blocklist.overlaps(range)) {
+            eprintln!(
+                "Warning: some targets are private or reserved and will be skipped, \
+                 scan them with --no-default-blocklist"
+            );
         }
-        None => interface_data = scan::network_data::InterfaceData::fetch_default(),
     }
 
+    let ports = if args.probe.uses_ports() {
+        assert!(
+            !args.ports.is_empty(),
+            "--port is required unless probing with icmp-echo"
+        );
+        expand_ports(&args.ports)
+    } else {
+        vec![0]
+    };
+
+    // Resuming drops the output written past the checkpoint, which stdout can't take back
+    assert!(
+        args.resume.is_none() || !matches!(args.output.as_deref(), None | Some("-")),
+        "--resume needs -o naming the output file of the interrupted scan"
+    );
+    let fields = fields_or_default(args.output_format, args.fields);
+    // Everything else a resumed scan must keep, the probes sent and the
+    // layout of the output it appends to
+    let settings = [
+        args.probe.to_string(),
+        format!("{:?}", args.tcp_options),
+        args.probes.to_string(),
+        format!("{:?}", args.output_format),
+        format!("{:?}", fields),
+    ];
+    let fingerprint = fingerprint(&targets, &ports, &blocklist, &settings);
+
+    let resume = args
+        .resume
+        .as_ref()
+        .map(|path| Checkpoint::load(path).unwrap_or_else(|err| panic!("{}", err)));
+    let (seed, source_ports, shard, shards) = match &resume {
+        Some(checkpoint) => {
+            assert_eq!(
+                checkpoint.fingerprint, fingerprint,
+                "Targets, ports, exclusions, probes or output format differ from the checkpointed scan"
+            );
+            (
+                checkpoint.seed,
+                checkpoint.source_ports.clone(),
+                checkpoint.shard,
+                checkpoint.shards,
+            )
+        }
+        None => (
+            args.seed.unwrap_or_else(|| rand::thread_rng().gen()),
+            args.source_ports
+                .map(|ports| ports.start..=ports.end)
+                .unwrap_or_else(CookieHasher::ephemeral_range_from_proc),
+            args.shard,
+            args.shards,
+        ),
+    };
+
+    assert!(
+        shard < shards,
+        "Shard {} out of range, --shards is {}",
+        shard,
+        shards
+    );
+
+    // Both are needed to replay the scan on another host
+    eprintln!("Seed: {}", seed);
+    eprintln!("Source ports: {}-{}", source_ports.start(), source_ports.end());
+
     let run_state = RunState::new();
 
-    let (output, output_handle) = FileOut::new(args.output);
+    let resume_offset = resume.as_ref().map(|checkpoint| checkpoint.output_offset);
+    let out: Box<dyn Write + Send> = match args.output.as_deref() {
+        None | Some("-") => Box::new(io::stdout()),
+        Some(path) => Box::new(output::open_file(path, resume_offset)),
+    };
+    let info = ScanInfo {
+        seed,
+        source_ports: source_ports.clone(),
+        probe: args.probe,
+        ports: ports.clone(),
+        start: SystemTime::now(),
+    };
+    let (output, output_handle) =
+        OutputWriter::new(args.output_format.build(out, fields, info), resume_offset);
+
+    let config = scan::ScanConfig {
+        seed,
+        source_ports,
+        fingerprint,
+        probe: args.probe,
+        tcp_options: args.tcp_options,
+        ttl: args.ttl,
+        probes: args.probes,
+        probe_delay: Duration::from_millis(args.probe_delay),
+        shard,
+        shards,
+        ports,
+        targets,
+        blocklist,
+        rate: args.rate,
+        bandwidth: args.bandwidth,
+        report_closed: args.report_closed || args.probe.only_finds_closed(),
+        cooldown: Duration::from_secs(args.cooldown),
+        checkpoint: args.checkpoint.or(args.resume),
+        checkpoint_interval: Duration::from_secs(args.checkpoint_interval),
+        resume,
+    };
 
     let scanner = scan::Scanner::new(
-        args.port,
+        config,
         interface_data,
         run_state.clone(),
-        Box::new(output),
+        output,
         output_handle,
     );
     let scan = thread::spawn(|| scanner.scan());
@@ -87,10 +364,35 @@ fn main() {
 
     let mut buf = String::new();
     loop {
-        stdin.read_line(&mut buf).unwrap();
+        // Nothing more can toggle the pause once stdin is closed, the scan
+        // just runs to the end
+        if stdin.read_line(&mut buf).unwrap_or(0) == 0 {
+            if *run_state.paused.lock().unwrap() {
+                run_state.toogle();
+            }
+            scan.join().unwrap();
+            break;
+        }
         run_state.toogle();
         if scan.is_finished() {
             break;
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_si_accepts_suffixes() {
+        assert_eq!(parse_si("250"), Ok(250));
+        assert_eq!(parse_si("10k"), Ok(10_000));
+        assert_eq!(parse_si("10K"), Ok(10_000));
+        assert_eq!(parse_si("2M"), Ok(2_000_000));
+        assert_eq!(parse_si("1g"), Ok(1_000_000_000));
+        for s in ["", "0", "0K", "K", "1.5M", "-1", "10T", "18446744073709551615G"] {
+            assert!(parse_si(s).is_err(), "{}", s);
+        }
+    }
+}
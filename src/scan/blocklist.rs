@@ -0,0 +1,148 @@
+use std::fs;
+use std::net::Ipv4Addr;
+use std::str::FromStr;
+
+use super::target::{merge_ranges, IpRange};
+
+/// IANA IPv4 special-purpose address registry, along with multicast and reserved space
+pub const DEFAULT_BLOCKLIST: [&str; 15] = [
+    "0.0.0.0/8",       // "This network"
+    "10.0.0.0/8",      // Private-use
+    "100.64.0.0/10",   // Shared address space
+    "127.0.0.0/8",     // Loopback
+    "169.254.0.0/16",  // Link local
+    "172.16.0.0/12",   // Private-use
+    "192.0.0.0/24",    // IETF protocol assignments
+    "192.0.2.0/24",    // Documentation (TEST-NET-1)
+    "192.88.99.0/24",  // Deprecated 6to4 relay anycast
+    "192.168.0.0/16",  // Private-use
+    "198.18.0.0/15",   // Benchmarking
+    "198.51.100.0/24", // Documentation (TEST-NET-2)
+    "203.0.113.0/24",  // Documentation (TEST-NET-3)
+    "224.0.0.0/4",     // Multicast
+    "240.0.0.0/4",     // Reserved for future use and limited broadcast
+];
+
+/// Sorted set of disjoint ranges that must never be probed
+#[derive(Clone, Debug, Default)]
+pub struct Blocklist {
+    ranges: Vec<IpRange>,
+}
+
+impl Blocklist {
+    pub fn new(ranges: Vec<IpRange>) -> Self {
+        Self {
+            ranges: merge_ranges(ranges),
+        }
+    }
+
+    pub fn default_ranges() -> Vec<IpRange> {
+        DEFAULT_BLOCKLIST
+            .iter()
+            .map(|range| IpRange::from_str(range).unwrap())
+            .collect()
+    }
+
+    /// Sorted disjoint ranges making up the list
+    pub fn ranges(&self) -> &[IpRange] {
+        &self.ranges
+    }
+
+    /// Reads one range per line, `#` starts a comment
+    pub fn parse_file(path: &str) -> Result<Vec<IpRange>, String> {
+        let content = fs::read_to_string(path)
+            .map_err(|err| format!("Failed reading exclude file {}: {}", path, err))?;
+
+        let mut ranges = vec![];
+        for (line_number, line) in content.lines().enumerate() {
+            let line = match line.split_once('#') {
+                Some((line, _comment)) => line,
+                None => line,
+            }
+            .trim();
+            if line.is_empty() {
+                continue;
+            }
+            let range = IpRange::from_str(line)
+                .map_err(|err| format!("{}:{}: {}", path, line_number + 1, err))?;
+            ranges.push(range);
+        }
+
+        Ok(ranges)
+    }
+
+    /// Whether every address of `range` is excluded
+    pub fn covers(&self, range: &IpRange) -> bool {
+        let idx = self.ranges.partition_point(|blocked| blocked.end < range.start);
+        match self.ranges.get(idx) {
+            Some(blocked) => blocked.start <= range.start && range.end <= blocked.end,
+            None => false,
+        }
+    }
+
+    /// Whether some address of `range` is excluded
+    pub fn overlaps(&self, range: &IpRange) -> bool {
+        let idx = self.ranges.partition_point(|blocked| blocked.end < range.start);
+        match self.ranges.get(idx) {
+            Some(blocked) => blocked.start <= range.end,
+            None => false,
+        }
+    }
+
+    pub fn contains(&self, ip: Ipv4Addr) -> bool {
+        let ip = ip.to_bits();
+        let idx = self.ranges.partition_point(|range| range.end < ip);
+        match self.ranges.get(idx) {
+            Some(range) => range.start <= ip,
+            None => false,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn range(s: &str) -> IpRange {
+        IpRange::from_str(s).unwrap()
+    }
+
+    #[test]
+    fn contains_checks_range_bounds() {
+        let blocklist = Blocklist::new(vec![range("10.0.0.0/24"), range("192.168.1.5")]);
+
+        assert!(blocklist.contains(Ipv4Addr::new(10, 0, 0, 0)));
+        assert!(blocklist.contains(Ipv4Addr::new(10, 0, 0, 255)));
+        assert!(blocklist.contains(Ipv4Addr::new(192, 168, 1, 5)));
+        assert!(!blocklist.contains(Ipv4Addr::new(9, 255, 255, 255)));
+        assert!(!blocklist.contains(Ipv4Addr::new(10, 0, 1, 0)));
+        assert!(!blocklist.contains(Ipv4Addr::new(192, 168, 1, 6)));
+        assert!(!Blocklist::default().contains(Ipv4Addr::new(10, 0, 0, 1)));
+    }
+
+    #[test]
+    fn covers_and_overlaps_ranges() {
+        let blocklist = Blocklist::new(Blocklist::default_ranges());
+
+        assert!(blocklist.covers(&range("10.0.0.0/24")));
+        assert!(blocklist.covers(&range("192.168.0.0/16")));
+        assert!(!blocklist.covers(&range("192.168.0.0/15")));
+        assert!(blocklist.overlaps(&range("192.168.0.0/15")));
+        assert!(!blocklist.covers(&range("8.8.8.0/24")));
+        assert!(!blocklist.overlaps(&range("8.8.8.0/24")));
+        assert!(blocklist.overlaps(&range("0.0.0.0/0")));
+    }
+
+    #[test]
+    fn parse_file_skips_comments_and_blank_lines() {
+        let path = std::env::temp_dir().join(format!("sint-blocklist-{}", std::process::id()));
+        fs::write(&path, "# private\n10.0.0.0/8  # rfc1918\n\n  1.2.3.4-1.2.3.9\n").unwrap();
+        let ranges = Blocklist::parse_file(path.to_str().unwrap());
+        fs::write(&path, "10.0.0.0/8\nnot an address\n").unwrap();
+        let invalid = Blocklist::parse_file(path.to_str().unwrap());
+        fs::remove_file(&path).unwrap();
+
+        assert_eq!(ranges.unwrap(), vec![range("10.0.0.0/8"), range("1.2.3.4-1.2.3.9")]);
+        assert!(invalid.unwrap_err().ends_with(":2: Invalid IPv4 address: not an address"));
+    }
+}
@@ -0,0 +1,338 @@
+use std::collections::{HashMap, VecDeque};
+use std::fs;
+use std::ops::RangeInclusive;
+use std::sync::atomic::Ordering;
+use std::time::{Duration, Instant};
+
+use super::blocklist::Blocklist;
+use super::logger::Stats;
+use super::output::OutputOffset;
+use super::target::TargetSet;
+
+/// Progress of a scan, enough to continue it from where it stopped
+///
+/// The position is the one of a little while before the checkpoint, so the
+/// replies to every probe sent before it, retries included, are in the
+/// output. A resumed scan probes the targets after it again, their results
+/// may show twice
+#[derive(Clone, Debug)]
+pub struct Checkpoint {
+    pub seed: u64,
+    pub source_ports: RangeInclusive<u16>,
+    pub shard: u32,
+    pub shards: u32,
+    /// `fingerprint` of the scan, a resumed scan must match it
+    pub fingerprint: u64,
+    pub elapsed: Duration,
+    pub stats: Stats,
+    /// Bytes of output written, anything past it is dropped when resuming
+    pub output_offset: u64,
+}
+
+impl Checkpoint {
+    /// Writes the checkpoint next to `path` first, so a crash never leaves a torn file
+    pub fn save(&self, path: &str) -> std::io::Result<()> {
+        let content = format!(
+            "seed={}\nsource_port_start={}\nsource_port_end={}\nshard={}\nshards={}\nfingerprint={}\nelapsed={}\nposition={}\nsent={}\nreceived={}\nskipped={}\nduplicates={}\noutput_offset={}\n",
+            self.seed,
+            self.source_ports.start(),
+            self.source_ports.end(),
+            self.shard,
+            self.shards,
+            self.fingerprint,
+            self.elapsed.as_secs(),
+            self.stats.position,
+            self.stats.sent,
+            self.stats.received,
+            self.stats.skipped,
+            self.stats.duplicates,
+            self.output_offset,
+        );
+
+        let tmp_path = format!("{}.tmp", path);
+        fs::write(&tmp_path, content)?;
+        fs::rename(&tmp_path, path)
+    }
+
+    pub fn load(path: &str) -> Result<Self, String> {
+        let content = fs::read_to_string(path)
+            .map_err(|err| format!("Failed reading checkpoint {}: {}", path, err))?;
+
+        let values: HashMap<&str, &str> = content
+            .lines()
+            .filter_map(|line| line.split_once('='))
+            .collect();
+        let get = |key: &str| -> Result<u64, String> {
+            values
+                .get(key)
+                .ok_or_else(|| format!("Checkpoint {} is missing {}", path, key))?
+                .parse::<u64>()
+                .map_err(|_| format!("Checkpoint {} has an invalid {}", path, key))
+        };
+
+        let mut stats = Stats::new();
+        stats.position = get("position")?;
+        stats.sent = get("sent")?;
+        stats.received = get("received")?;
+        stats.skipped = get("skipped")?;
+        stats.duplicates = get("duplicates")?;
+
+        Ok(Self {
+            seed: get("seed")?,
+            source_ports: get("source_port_start")? as u16..=get("source_port_end")? as u16,
+            shard: get("shard")? as u32,
+            shards: get("shards")? as u32,
+            fingerprint: get("fingerprint")?,
+            elapsed: Duration::from_secs(get("elapsed")?),
+            stats,
+            output_offset: get("output_offset")?,
+        })
+    }
+}
+
+/// Hash of the targets, ports and exclusions of a scan, along with the
+/// `settings` that change the probes sent or the layout of the output
+pub fn fingerprint(
+    targets: &TargetSet,
+    ports: &[u16],
+    blocklist: &Blocklist,
+    settings: &[String],
+) -> u64 {
+    // FNV-1a, unlike `DefaultHasher` it is stable across Rust versions
+    let mut hash: u64 = 0xcbf2_9ce4_8422_2325;
+    let mut feed = |bytes: &[u8]| {
+        for byte in bytes {
+            hash ^= *byte as u64;
+            hash = hash.wrapping_mul(0x0100_0000_01b3);
+        }
+    };
+
+    // Every list is prefixed by its length so moving an item from one list
+    // to the next changes the hash
+    for ranges in [targets.ranges(), blocklist.ranges()] {
+        feed(&(ranges.len() as u64).to_be_bytes());
+        for range in ranges {
+            feed(&range.start.to_be_bytes());
+            feed(&range.end.to_be_bytes());
+        }
+    }
+    feed(&(ports.len() as u64).to_be_bytes());
+    for port in ports {
+        feed(&port.to_be_bytes());
+    }
+    for setting in settings {
+        feed(&(setting.len() as u64).to_be_bytes());
+        feed(setting.as_bytes());
+    }
+
+    hash
+}
+
+/// Periodically saves the progress of the running scan
+pub struct Checkpointer {
+    path: String,
+    interval: Duration,
+    last_save: Instant,
+    /// Time the replies to a probe, retries included, may take to come in
+    lag: Duration,
+    /// Stats of the last `lag`, along with the newest ones older than that
+    samples: VecDeque<(Instant, Stats)>,
+    checkpoint: Checkpoint,
+    output_offset: OutputOffset,
+}
+
+impl Checkpointer {
+    pub fn new(
+        path: String,
+        interval: Duration,
+        lag: Duration,
+        checkpoint: Checkpoint,
+        output_offset: OutputOffset,
+    ) -> Self {
+        Self {
+            path,
+            interval,
+            last_save: Instant::now(),
+            lag,
+            samples: VecDeque::from([(Instant::now(), checkpoint.stats.clone())]),
+            checkpoint,
+            output_offset,
+        }
+    }
+
+    /// Saves the checkpoint if `interval` went by since the last one, or when
+    /// `force` is set once every reply is in
+    pub fn tick(&mut self, elapsed: Duration, stats: &Stats, force: bool) {
+        let now = Instant::now();
+        self.samples.push_back((now, stats.clone()));
+        while self
+            .samples
+            .get(1)
+            .is_some_and(|(time, _)| now - *time >= self.lag)
+        {
+            self.samples.pop_front();
+        }
+
+        if !force && self.last_save.elapsed() < self.interval {
+            return;
+        }
+        self.last_save = now;
+
+        // Replies to the probes sent before the sampled position came in by
+        // now, so they are all below the offset
+        let mut saved = if force {
+            stats.clone()
+        } else {
+            self.samples[0].1.clone()
+        };
+        saved.received = stats.received;
+        saved.duplicates = stats.duplicates;
+
+        self.checkpoint.output_offset = self.output_offset.load(Ordering::SeqCst);
+        self.checkpoint.elapsed = elapsed;
+        self.checkpoint.stats = saved;
+
+        if let Err(err) = self.checkpoint.save(&self.path) {
+            eprintln!("Failed saving checkpoint {}: {}", self.path, err);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::scan::target::IpRange;
+    use std::str::FromStr;
+
+    fn targets(s: &str) -> TargetSet {
+        TargetSet::new(vec![IpRange::from_str(s).unwrap()])
+    }
+
+    #[test]
+    fn save_and_load_round_trip() {
+        let mut stats = Stats::new();
+        stats.position = 1234;
+        stats.sent = 1200;
+        stats.received = 56;
+        stats.skipped = 34;
+        stats.duplicates = 7;
+        let checkpoint = Checkpoint {
+            seed: u64::MAX,
+            source_ports: 32768..=60999,
+            shard: 2,
+            shards: 3,
+            fingerprint: 42,
+            elapsed: Duration::from_secs(90),
+            stats,
+            output_offset: 4096,
+        };
+
+        let path = std::env::temp_dir().join(format!("sint-checkpoint-{}", std::process::id()));
+        let path = path.to_str().unwrap();
+        checkpoint.save(path).unwrap();
+        let loaded = Checkpoint::load(path);
+        fs::write(path, "seed=1\n").unwrap();
+        let truncated = Checkpoint::load(path);
+        fs::remove_file(path).unwrap();
+
+        let loaded = loaded.unwrap();
+        assert_eq!(loaded.seed, checkpoint.seed);
+        assert_eq!(loaded.source_ports, checkpoint.source_ports);
+        assert_eq!((loaded.shard, loaded.shards), (2, 3));
+        assert_eq!(loaded.fingerprint, 42);
+        assert_eq!(loaded.elapsed, checkpoint.elapsed);
+        assert_eq!(loaded.stats.position, 1234);
+        assert_eq!(loaded.stats.sent, 1200);
+        assert_eq!(loaded.stats.received, 56);
+        assert_eq!(loaded.stats.skipped, 34);
+        assert_eq!(loaded.stats.duplicates, 7);
+        assert_eq!(loaded.output_offset, 4096);
+        assert!(truncated.unwrap_err().contains("missing"));
+    }
+
+    #[test]
+    fn fingerprint_covers_targets_ports_exclusions_and_settings() {
+        let blocklist = Blocklist::new(Blocklist::default_ranges());
+        let settings = |settings: &[&str]| -> Vec<String> {
+            settings.iter().map(|setting| setting.to_string()).collect()
+        };
+        let syn = settings(&["tcp-syn", "text"]);
+        let base = fingerprint(&targets("8.8.8.0/24"), &[80], &blocklist, &syn);
+
+        assert_eq!(
+            base,
+            fingerprint(&targets("8.8.8.0/24"), &[80], &blocklist, &syn)
+        );
+        assert_ne!(
+            base,
+            fingerprint(&targets("8.8.4.0/24"), &[80], &blocklist, &syn)
+        );
+        assert_ne!(
+            base,
+            fingerprint(&targets("8.8.8.0/24"), &[443], &blocklist, &syn)
+        );
+        assert_ne!(
+            base,
+            fingerprint(&targets("8.8.8.0/24"), &[80], &Blocklist::default(), &syn)
+        );
+        for other in [
+            &["tcp-syn", "binary"][..],
+            &["tcp-ack", "text"],
+            &["tcp-syntext"],
+        ] {
+            assert_ne!(
+                base,
+                fingerprint(&targets("8.8.8.0/24"), &[80], &blocklist, &settings(other))
+            );
+        }
+    }
+
+    #[test]
+    fn saves_the_position_from_before_the_lag() {
+        let path = std::env::temp_dir().join(format!("sint-checkpointer-{}", std::process::id()));
+        let path = path.to_str().unwrap().to_string();
+        let checkpoint = Checkpoint {
+            seed: 1,
+            source_ports: 1024..=65535,
+            shard: 0,
+            shards: 1,
+            fingerprint: 0,
+            elapsed: Duration::ZERO,
+            stats: Stats::new(),
+            output_offset: 0,
+        };
+        let offset = OutputOffset::default();
+        let checkpointer = |lag: Duration| {
+            Checkpointer::new(
+                path.clone(),
+                Duration::ZERO,
+                lag,
+                checkpoint.clone(),
+                offset.clone(),
+            )
+        };
+        let mut stats = Stats::new();
+        stats.position = 100;
+        stats.sent = 100;
+        stats.received = 10;
+        offset.store(250, Ordering::SeqCst);
+
+        // Replies to the last probes may still come, the position stays behind
+        let mut lagging = checkpointer(Duration::from_secs(3600));
+        lagging.tick(Duration::from_secs(5), &stats, false);
+        let saved = Checkpoint::load(&path).unwrap();
+        assert_eq!((saved.stats.position, saved.stats.sent), (0, 0));
+        assert_eq!(saved.stats.received, 10);
+        assert_eq!(saved.output_offset, 250);
+
+        // Once the scan is over every reply is in
+        lagging.tick(Duration::from_secs(6), &stats, true);
+        assert_eq!(Checkpoint::load(&path).unwrap().stats.position, 100);
+
+        let mut current = checkpointer(Duration::ZERO);
+        current.tick(Duration::from_secs(5), &stats, false);
+        let saved = Checkpoint::load(&path);
+        fs::remove_file(&path).unwrap();
+        assert_eq!(saved.unwrap().stats.position, 100);
+    }
+}
@@ -1,49 +1,61 @@
 use aes::cipher::{generic_array::GenericArray, BlockEncrypt, KeyInit};
 use aes::Aes128;
 
-use rand::{self, Rng};
+use rand::Rng;
 use std::net::Ipv4Addr;
 use std::ops::RangeInclusive;
 
+/// Stateless proof that a reply answers one of our probes, the port,
+/// sequence number and IP identification of the probe are all taken from it
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Cookie {
+    pub port: u16,
+    pub seq: u32,
+    pub ip_id: u16,
+}
+
 #[derive(Clone, Debug)]
 pub struct CookieHasher {
     cipher: Aes128,
-    ephemeral_range: RangeInclusive<u16>,
+    source_ports: RangeInclusive<u16>,
 }
 
 impl CookieHasher {
-    pub fn new() -> Self {
+    /// The cookie ports are taken from `source_ports`, replaying a scan needs
+    /// the same range along with the same seed
+    pub fn new(rng: &mut impl Rng, source_ports: RangeInclusive<u16>) -> Self {
         let mut secret: Vec<u8> = vec![];
-        let mut rng = rand::thread_rng();
         for _i in 0..16 {
             secret.push(rng.gen())
         }
         let key = GenericArray::clone_from_slice(&secret[..]);
         let cipher = Aes128::new(&key);
-        let ephemeral_range = Self::fetch_ephemeral_range_from_proc();
         Self {
             cipher,
-            ephemeral_range,
+            source_ports,
         }
     }
 
-    pub fn get_port_cookie(&self, src_ip: Ipv4Addr, dest_ip: Ipv4Addr) -> u16 {
+    pub fn get_cookie(&self, src_ip: Ipv4Addr, dest_ip: Ipv4Addr, dest_port: u16) -> Cookie {
         let mut buf = [0u8; 16];
         buf[..4].copy_from_slice(&src_ip.octets());
         buf[4..8].copy_from_slice(&dest_ip.octets());
-        buf[8..16].copy_from_slice(&[0u8; 8]);
-        let mut data = GenericArray::clone_from_slice(&mut buf);
+        buf[8..10].copy_from_slice(&dest_port.to_be_bytes());
+        buf[10..16].copy_from_slice(&[0u8; 6]);
+        let mut data = GenericArray::clone_from_slice(&buf);
         self.cipher.encrypt_block(&mut data);
         let hash = u16::from_be_bytes([data[0], data[15]]);
-        return (hash % self.ephemeral_range.len() as u16) + self.ephemeral_range.start();
-    }
-
-    pub fn check_port_cookie(&self, ip: Ipv4Addr, dst_ip: Ipv4Addr, dst_port: u16) -> bool {
-        let hash = self.get_port_cookie(ip, dst_ip);
-        return hash == dst_port;
+        Cookie {
+            // In u32 as the full range of 65536 ports doesn't fit a u16
+            port: (hash as u32 % self.source_ports.len() as u32) as u16 + self.source_ports.start(),
+            seq: u32::from_be_bytes([data[1], data[2], data[3], data[4]]),
+            ip_id: u16::from_be_bytes([data[5], data[6]]),
+        }
     }
 
-    fn fetch_ephemeral_range_from_proc() -> RangeInclusive<u16> {
+    /// Ports the kernel picks for outgoing connections, probes sent from them
+    /// look like ordinary clients
+    pub fn ephemeral_range_from_proc() -> RangeInclusive<u16> {
         let range_data = std::fs::read_to_string("/proc/sys/net/ipv4/ip_local_port_range").unwrap();
         let range_data: Vec<&str> = range_data.split_whitespace().collect();
         let start_ephemeral = range_data[0].parse::<u16>().unwrap();
@@ -51,3 +63,30 @@ impl CookieHasher {
         start_ephemeral..=end_ephemeral
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rand::SeedableRng;
+    use rand_chacha::ChaCha8Rng;
+
+    #[test]
+    fn cookies_depend_only_on_seed_and_source_ports() {
+        let src = Ipv4Addr::new(192, 0, 2, 1);
+        let hasher = |ports| CookieHasher::new(&mut ChaCha8Rng::seed_from_u64(42), ports);
+
+        let first = hasher(32768..=60999);
+        let again = hasher(32768..=60999);
+        for last in 0..=255 {
+            let dest = Ipv4Addr::new(198, 51, 100, last);
+            let cookie = first.get_cookie(src, dest, 443);
+            assert_eq!(cookie, again.get_cookie(src, dest, 443));
+            assert!((32768..=60999).contains(&cookie.port));
+        }
+
+        let single = hasher(40000..=40000);
+        assert_eq!(single.get_cookie(src, src, 80).port, 40000);
+        // The whole port space doesn't overflow the modulo
+        hasher(0..=65535).get_cookie(src, src, 80);
+    }
+}
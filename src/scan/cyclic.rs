@@ -0,0 +1,288 @@
+use rand::Rng;
+
+use std::net::SocketAddrV4;
+
+use super::target::TargetSet;
+
+/// Walks the multiplicative group of integers modulo a prime just above `len`,
+/// every element `x` of the group stands for the index `x - 1`, indices past
+/// `len` are skipped
+///
+/// The cycle is split in `shards` contiguous slices, only the slice of `shard`
+/// is walked
+pub struct Cyclic {
+    pub prime: u64,
+    pub generator: u64,
+    current: u64,
+    len: u64,
+    remaining: u64,
+    position: u64,
+}
+
+impl Iterator for Cyclic {
+    type Item = u64;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        while self.remaining > 0 {
+            self.remaining -= 1;
+            self.position += 1;
+            let index = self.current - 1;
+            self.current = mul_mod(self.current, self.generator, self.prime);
+            if index < self.len {
+                return Some(index);
+            }
+        }
+        None
+    }
+}
+
+impl Cyclic {
+    pub fn new(len: u64, shard: u32, shards: u32, rng: &mut impl Rng) -> Self {
+        let prime = next_prime(len.max(2));
+        let factors = prime_factors(prime - 1);
+
+        // A random primitive root keeps consecutive elements far apart, so
+        // neighbouring addresses are never probed in bursts
+        let generator = loop {
+            let candidate = rng.gen_range(2..prime);
+            if factors
+                .iter()
+                .all(|factor| pow_mod(candidate, (prime - 1) / factor, prime) != 1)
+            {
+                break candidate;
+            }
+        };
+
+        let start = rng.gen_range(1..prime);
+
+        let order = prime as u128 - 1;
+        let first = (order * shard as u128 / shards as u128) as u64;
+        let last = (order * (shard as u128 + 1) / shards as u128) as u64;
+
+        Self {
+            prime,
+            generator,
+            current: mul_mod(start, pow_mod(generator, first, prime), prime),
+            len,
+            remaining: last - first,
+            position: 0,
+        }
+    }
+
+    /// Number of elements of the slice walked so far
+    pub fn position(&self) -> u64 {
+        self.position
+    }
+
+    /// Jumps over the next `steps` elements without yielding them
+    pub fn advance(&mut self, steps: u64) {
+        let steps = steps.min(self.remaining);
+        self.current = mul_mod(
+            self.current,
+            pow_mod(self.generator, steps, self.prime),
+            self.prime,
+        );
+        self.remaining -= steps;
+        self.position += steps;
+    }
+
+    /// Number of indices left to walk, exact for a single shard
+    pub fn estimated_len(&self) -> u64 {
+        (self.len as u128 * self.remaining as u128 / (self.prime as u128 - 1)) as u64
+    }
+}
+
+fn mul_mod(a: u64, b: u64, modulus: u64) -> u64 {
+    ((a as u128 * b as u128) % modulus as u128) as u64
+}
+
+fn pow_mod(mut base: u64, mut exp: u64, modulus: u64) -> u64 {
+    let mut result = 1;
+    base %= modulus;
+    while exp > 0 {
+        if exp & 1 == 1 {
+            result = mul_mod(result, base, modulus);
+        }
+        base = mul_mod(base, base, modulus);
+        exp >>= 1;
+    }
+    result
+}
+
+/// Deterministic Miller-Rabin, these bases are enough for every u64
+fn is_prime(n: u64) -> bool {
+    const BASES: [u64; 12] = [2, 3, 5, 7, 11, 13, 17, 19, 23, 29, 31, 37];
+
+    if n < 2 {
+        return false;
+    }
+    for base in BASES {
+        if n.is_multiple_of(base) {
+            return n == base;
+        }
+    }
+
+    let mut d = n - 1;
+    let mut r = 0;
+    while d.is_multiple_of(2) {
+        d /= 2;
+        r += 1;
+    }
+
+    'witness: for base in BASES {
+        let mut x = pow_mod(base, d, n);
+        if x == 1 || x == n - 1 {
+            continue;
+        }
+        for _ in 1..r {
+            x = mul_mod(x, x, n);
+            if x == n - 1 {
+                continue 'witness;
+            }
+        }
+        return false;
+    }
+    true
+}
+
+fn next_prime(n: u64) -> u64 {
+    let mut candidate = n + 1;
+    while !is_prime(candidate) {
+        candidate += 1;
+    }
+    candidate
+}
+
+/// Distinct prime factors of `n`, by trial division
+fn prime_factors(mut n: u64) -> Vec<u64> {
+    let mut factors = vec![];
+    let mut divisor = 2;
+    while divisor * divisor <= n {
+        if n.is_multiple_of(divisor) {
+            factors.push(divisor);
+            while n.is_multiple_of(divisor) {
+                n /= divisor;
+            }
+        }
+        divisor += if divisor == 2 { 1 } else { 2 };
+    }
+    if n > 1 {
+        factors.push(n);
+    }
+    factors
+}
+
+/// Walks every (address, port) pair of the targets in a random order
+pub struct IPv4Iterator {
+    ports: Vec<u16>,
+    targets: TargetSet,
+    cyclic: Cyclic,
+}
+
+impl IPv4Iterator {
+    pub fn new(
+        ports: Vec<u16>,
+        targets: TargetSet,
+        shard: u32,
+        shards: u32,
+        rng: &mut impl Rng,
+    ) -> Self {
+        Self {
+            cyclic: Cyclic::new(targets.len() * ports.len() as u64, shard, shards, rng),
+            ports,
+            targets,
+        }
+    }
+
+    pub fn estimated_len(&self) -> u64 {
+        self.cyclic.estimated_len()
+    }
+
+    pub fn position(&self) -> u64 {
+        self.cyclic.position()
+    }
+
+    pub fn advance(&mut self, steps: u64) {
+        self.cyclic.advance(steps)
+    }
+}
+
+impl Iterator for IPv4Iterator {
+    type Item = SocketAddrV4;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let index = self.cyclic.next()?;
+        let ip = self.targets.get(index % self.targets.len());
+        let port = self.ports[(index / self.targets.len()) as usize];
+        Some(SocketAddrV4::new(ip, port))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rand::SeedableRng;
+    use rand_chacha::ChaCha8Rng;
+
+    #[test]
+    fn is_prime_matches_trial_division() {
+        let trial = |n: u64| n >= 2 && (2..n).take_while(|d| d * d <= n).all(|d| !n.is_multiple_of(d));
+        for n in 0..5000 {
+            assert_eq!(is_prime(n), trial(n), "{}", n);
+        }
+        assert!(is_prime(4_294_967_311));
+        assert!(is_prime(18_446_744_073_709_551_557));
+        assert!(!is_prime(3_215_031_751));
+    }
+
+    #[test]
+    fn prime_factors_are_distinct() {
+        assert_eq!(prime_factors(2), vec![2]);
+        assert_eq!(prime_factors(360), vec![2, 3, 5]);
+        assert_eq!(prime_factors(4_294_967_310), vec![2, 3, 5, 131, 364289]);
+        assert_eq!(prime_factors(97), vec![97]);
+    }
+
+    #[test]
+    fn visits_every_index_once() {
+        let mut rng = ChaCha8Rng::seed_from_u64(1);
+        for len in [1, 2, 10, 1000, 4099] {
+            let mut indices: Vec<u64> = Cyclic::new(len, 0, 1, &mut rng).collect();
+            indices.sort_unstable();
+            assert_eq!(indices, (0..len).collect::<Vec<_>>(), "{}", len);
+        }
+    }
+
+    #[test]
+    fn shards_are_disjoint_and_complete() {
+        let len = 1000;
+        for shards in [1, 2, 3, 7] {
+            let mut indices = vec![];
+            for shard in 0..shards {
+                let mut rng = ChaCha8Rng::seed_from_u64(7);
+                indices.extend(Cyclic::new(len, shard, shards, &mut rng));
+            }
+            indices.sort_unstable();
+            assert_eq!(indices, (0..len).collect::<Vec<_>>(), "{}", shards);
+        }
+    }
+
+    #[test]
+    fn advance_resumes_at_the_same_position() {
+        let walk = || Cyclic::new(5000, 1, 3, &mut ChaCha8Rng::seed_from_u64(3));
+        let full: Vec<u64> = walk().collect();
+
+        let mut first = walk();
+        let head: Vec<u64> = first.by_ref().take(100).collect();
+        let mut resumed = walk();
+        resumed.advance(first.position());
+        assert_eq!(resumed.position(), first.position());
+
+        let rest: Vec<u64> = resumed.collect();
+        assert_eq!([head, rest].concat(), full);
+
+        let mut past_end = walk();
+        past_end.advance(u64::MAX);
+        assert_eq!(past_end.next(), None);
+    }
+}
@@ -0,0 +1,219 @@
+use std::net::SocketAddrV4;
+
+use super::target::TargetSet;
+
+/// Largest bitmap kept for exact deduplication, 128 MiB
+const MAX_EXACT_BITS: u64 = 1 << 30;
+
+/// Entries a filter holds before the older one is cleared and takes over
+const CAPACITY: usize = 1 << 20;
+/// 16 bits per entry with 11 hashes keep false positives around 0.05%
+const BITS: usize = CAPACITY * 16;
+const HASHES: u64 = 11;
+
+struct BloomFilter {
+    bits: Vec<u64>,
+}
+
+impl BloomFilter {
+    fn new() -> Self {
+        Self {
+            bits: vec![0; BITS / 64],
+        }
+    }
+
+    /// Bit indices of `key`, by double hashing
+    fn indices(key: u64) -> impl Iterator<Item = usize> {
+        let h1 = splitmix64(key);
+        let h2 = splitmix64(h1) | 1;
+        (0..HASHES).map(move |i| (h1.wrapping_add(i.wrapping_mul(h2)) as usize) % BITS)
+    }
+
+    fn contains(&self, key: u64) -> bool {
+        Self::indices(key).all(|i| self.bits[i / 64] & (1 << (i % 64)) != 0)
+    }
+
+    fn insert(&mut self, key: u64) {
+        for i in Self::indices(key) {
+            self.bits[i / 64] |= 1 << (i % 64);
+        }
+    }
+
+    fn clear(&mut self) {
+        self.bits.fill(0);
+    }
+}
+
+fn splitmix64(mut x: u64) -> u64 {
+    x = x.wrapping_add(0x9e3779b97f4a7c15);
+    x = (x ^ (x >> 30)).wrapping_mul(0xbf58476d1ce4e5b9);
+    x = (x ^ (x >> 27)).wrapping_mul(0x94d049bb133111eb);
+    x ^ (x >> 31)
+}
+
+/// One bit per target, indexed like the permutation
+struct Bitmap {
+    bits: Vec<u64>,
+    targets: TargetSet,
+    ports: Vec<u16>,
+}
+
+impl Bitmap {
+    fn index(&self, addr: SocketAddrV4) -> Option<u64> {
+        let ip = self.targets.index_of(*addr.ip())?;
+        let port = self.ports.binary_search(&addr.port()).ok()? as u64;
+        Some(port * self.targets.len() + ip)
+    }
+
+    fn insert(&mut self, addr: SocketAddrV4) -> bool {
+        // Only replies validated by their cookie get here, they come from the targets
+        let Some(index) = self.index(addr) else {
+            return true;
+        };
+        let (word, bit) = ((index / 64) as usize, 1 << (index % 64));
+        let new = self.bits[word] & bit == 0;
+        self.bits[word] |= bit;
+        new
+    }
+}
+
+/// Two bloom filters taking turns, when the current one is full the older
+/// one is cleared and becomes current, so at least the last `CAPACITY`
+/// results are always remembered. Replies come within seconds of the probes,
+/// by which time the targets forgotten stopped answering long ago
+struct RotatingBloom {
+    filters: [BloomFilter; 2],
+    current: usize,
+    inserted: usize,
+}
+
+impl RotatingBloom {
+    fn new() -> Self {
+        Self {
+            filters: [BloomFilter::new(), BloomFilter::new()],
+            current: 0,
+            inserted: 0,
+        }
+    }
+
+    fn insert(&mut self, addr: SocketAddrV4) -> bool {
+        let key = (u32::from(*addr.ip()) as u64) << 16 | addr.port() as u64;
+        if self.filters.iter().any(|filter| filter.contains(key)) {
+            return false;
+        }
+
+        if self.inserted == CAPACITY {
+            self.current ^= 1;
+            self.filters[self.current].clear();
+            self.inserted = 0;
+        }
+        self.filters[self.current].insert(key);
+        self.inserted += 1;
+        true
+    }
+}
+
+/// Remembers the targets already reported, whatever their status, so a
+/// target answering several copies of a probe differently shows once. A bitmap over the targets keeps
+/// it exact when it fits in `MAX_EXACT_BITS`, larger scans fall back to
+/// bloom filters in a fixed amount of memory, whose false positives drop up
+/// to 0.1% of the results
+pub struct Dedup {
+    seen: Seen,
+}
+
+enum Seen {
+    Exact(Bitmap),
+    Bloom(RotatingBloom),
+}
+
+impl Dedup {
+    pub fn new(targets: &TargetSet, ports: &[u16]) -> Self {
+        let bits = targets.len() * ports.len() as u64;
+        let seen = if bits > MAX_EXACT_BITS {
+            Seen::Bloom(RotatingBloom::new())
+        } else {
+            // Zeroed allocations are mapped lazily, untouched pages cost nothing
+            Seen::Exact(Bitmap {
+                bits: vec![0; bits.div_ceil(64) as usize],
+                targets: targets.clone(),
+                ports: ports.to_vec(),
+            })
+        };
+        Self { seen }
+    }
+
+    /// Records that `addr` answered, returns whether it was new
+    pub fn insert(&mut self, addr: SocketAddrV4) -> bool {
+        match &mut self.seen {
+            Seen::Exact(bitmap) => bitmap.insert(addr),
+            Seen::Bloom(bloom) => bloom.insert(addr),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::scan::target::IpRange;
+    use std::net::Ipv4Addr;
+    use std::str::FromStr;
+
+    fn addr(last: u8, port: u16) -> SocketAddrV4 {
+        SocketAddrV4::new(Ipv4Addr::new(192, 0, 2, last), port)
+    }
+
+    fn check_dedup(dedup: &mut Dedup) {
+        assert!(dedup.insert(addr(1, 80)));
+        assert!(!dedup.insert(addr(1, 80)));
+        assert!(dedup.insert(addr(1, 443)));
+        assert!(dedup.insert(addr(2, 80)));
+        assert!(!dedup.insert(addr(2, 80)));
+    }
+
+    #[test]
+    fn small_scans_are_deduplicated_exactly() {
+        let targets = TargetSet::new(vec![IpRange::from_str("192.0.2.0/24").unwrap()]);
+        let mut dedup = Dedup::new(&targets, &[80, 443]);
+        assert!(matches!(dedup.seen, Seen::Exact(_)));
+        check_dedup(&mut dedup);
+
+        for last in 0..=255 {
+            for port in [80, 443] {
+                let seen = matches!((last, port), (1, _) | (2, 80));
+                assert_eq!(dedup.insert(addr(last, port)), !seen);
+            }
+        }
+        assert!(dedup.insert(SocketAddrV4::new(Ipv4Addr::new(8, 8, 8, 8), 80)));
+    }
+
+    #[test]
+    fn large_scans_fall_back_to_bloom_filters() {
+        let mut dedup = Dedup::new(&TargetSet::default(), &[80, 443]);
+        assert!(matches!(dedup.seen, Seen::Bloom(_)));
+        check_dedup(&mut dedup);
+    }
+
+    #[test]
+    fn bloom_filters_rotate_when_full() {
+        let mut bloom = RotatingBloom::new();
+        let addr = |i: usize| SocketAddrV4::new(Ipv4Addr::from_bits(i as u32), 80);
+
+        assert!(bloom.insert(addr(0)));
+        let mut i = 1;
+        while bloom.current == 0 {
+            bloom.insert(addr(i));
+            i += 1;
+        }
+        // The full filter is still checked after the rotation
+        assert!(i > CAPACITY);
+        assert!(!bloom.insert(addr(0)));
+
+        // Until the next rotation clears it
+        while bloom.current == 1 {
+            bloom.insert(addr(i));
+            i += 1;
+        }
+        assert!(bloom.insert(addr(0)));
+    }
+}
@@ -4,12 +4,20 @@ use std::time::{Duration, Instant};
 
 use crate::SharedRunState;
 
-use super::{ControlRx, ControlTx};
+use super::checkpoint::Checkpointer;
+use super::{ControlRx, ControlTx, ThreadControlMessage};
 
 #[derive(Debug, Clone)]
 pub struct Stats {
     pub sent: u64,
     pub received: u64,
+    pub skipped: u64,
+    /// Replies from targets already reported
+    pub duplicates: u64,
+    /// Position of the sender in its slice of the permutation
+    pub position: u64,
+    /// When the last copy of the probes waiting in the retry queue is due
+    pub retries_end: Option<Instant>,
 }
 
 impl Stats {
@@ -17,6 +25,10 @@ impl Stats {
         Stats {
             sent: 0,
             received: 0,
+            skipped: 0,
+            duplicates: 0,
+            position: 0,
+            retries_end: None,
         }
     }
 }
@@ -26,21 +38,38 @@ pub type LoggerStats = Arc<Mutex<Stats>>;
 pub struct Logger {
     interval: Duration,
     start: Instant,
+    resumed: Duration,
+    targets: u64,
+    /// Time between the first and the last probe sent to a target
+    retry_tail: Duration,
     stats: LoggerStats,
+    checkpointer: Option<Checkpointer>,
     run_state: SharedRunState,
     control_rx: ControlRx,
 }
 
 impl Logger {
-    pub fn new(run_state: SharedRunState) -> (Self, LoggerStats, ControlTx) {
-        let stats = Arc::new(Mutex::new(Stats::new()));
+    /// `stats` and `resumed` carry the progress of a resumed scan
+    pub fn new(
+        run_state: SharedRunState,
+        targets: u64,
+        retry_tail: Duration,
+        stats: Stats,
+        resumed: Duration,
+        checkpointer: Option<Checkpointer>,
+    ) -> (Self, LoggerStats, ControlTx) {
+        let stats = Arc::new(Mutex::new(stats));
 
         let (control_tx, control_rx) = channel();
 
         let logger = Logger {
             interval: Duration::new(1, 0),
             start: Instant::now(),
+            resumed,
+            targets,
+            retry_tail,
             stats: stats.clone(),
+            checkpointer,
             run_state,
             control_rx,
         };
@@ -69,31 +98,62 @@ impl Logger {
         time
     }
 
-    pub fn log(self) {
+    pub fn log(mut self) {
         let mut paused_offset = Duration::new(0, 0);
+        let mut cooldown_end: Option<Duration> = None;
         loop {
             if *self.run_state.paused.lock().unwrap() {
                 let started = Instant::now();
                 self.run_state.act_state();
                 paused_offset += Instant::now() - started;
             }
-            if let Ok(_) = self.control_rx.try_recv() {
-                return;
+            let timespan = Instant::now() - self.start - paused_offset + self.resumed;
+            match self.control_rx.try_recv() {
+                Ok(ThreadControlMessage::Die) => {
+                    if let Some(checkpointer) = &mut self.checkpointer {
+                        checkpointer.tick(timespan, &self.stats.lock().unwrap(), true);
+                    }
+                    return;
+                }
+                Ok(ThreadControlMessage::Cooldown(cooldown)) => {
+                    cooldown_end = Some(timespan + cooldown);
+                }
+                Err(_) => {}
             }
-            let timespan = Instant::now() - self.start - paused_offset;
 
             {
                 let data = self.stats.lock().unwrap();
+                if let Some(checkpointer) = &mut self.checkpointer {
+                    checkpointer.tick(timespan, &data, false);
+                }
                 let send_kbps = (data.sent as f64 / (10u64.pow(3) as f64)) / timespan.as_secs_f64();
                 let recv_ps = data.received as f64 / timespan.as_secs_f64();
                 let time = Self::format_time(&timespan);
-                let remaining = Self::format_time(&Duration::new(
-                    (((timespan.as_secs() as f64 + 1.0) / ((data.sent as f64) + 1.0)) * ((2 as f64).powf(32.0) - data.sent as f64)) as u64,
-                    0,
-                ));
-                println!(
-                    "{}; Sent: {:.2} at {:.2} Kp/s; Received: {:.2} at {:.2} p/s; left: {}",
-                    time, data.sent, send_kbps, data.received, recv_ps, remaining
+                let remaining = match cooldown_end {
+                    Some(end) => format!(
+                        "cooldown {}",
+                        Self::format_time(&end.saturating_sub(timespan))
+                    ),
+                    // The position counts targets whatever the number of probes
+                    // sent to them, the last copies go out a retry tail later
+                    None => {
+                        let targets_left = if data.position < self.targets {
+                            Duration::new(
+                                (((timespan.as_secs() as f64 + 1.0) / ((data.position as f64) + 1.0)) * (self.targets as f64 - data.position as f64)) as u64,
+                                0,
+                            ) + self.retry_tail
+                        } else {
+                            Duration::ZERO
+                        };
+                        let retries_left = data
+                            .retries_end
+                            .map_or(Duration::ZERO, |end| end.saturating_duration_since(Instant::now()));
+                        Self::format_time(&targets_left.max(retries_left))
+                    }
+                };
+                eprintln!(
+                    "{}; Sent: {:.2} at {:.2} Kp/s; Received: {:.2} at {:.2} p/s; Duplicates: {}; Skipped: {}; left: {}",
+                    time, data.sent, send_kbps, data.received, recv_ps, data.duplicates, data.skipped, remaining
                 );
             }
             std::thread::sleep(self.interval);
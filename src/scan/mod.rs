@@ -1,22 +1,39 @@
 use std::thread; 
+use std::ops::RangeInclusive;
+use std::time::Duration;
 use crate::SharedRunState;
 use std::sync::mpsc::{Receiver, Sender};
 
+use rand::SeedableRng;
+use rand_chacha::ChaCha8Rng;
+
 use self::{
+    blocklist::Blocklist,
+    checkpoint::{Checkpoint, Checkpointer},
     cookie::CookieHasher,
-    logger::Logger,
-    packet_receiver::PacketReceiver,
-    packet_sender::PacketSender,
-    network_data::InterfaceData, output::{OutputHandle, Output},
+    cyclic::IPv4Iterator,
+    dedup::Dedup,
+    logger::{Logger, Stats},
+    packet_receiver::{PacketReceiver, Validator},
+    packet_sender::{PacketBuilder, PacketSender, RetryQueue},
+    probe::{tcp::TcpOptions, ProbeType},
+    rate_limiter::RateLimiter,
+    network_data::InterfaceData, output::{OutputHandle, OutputWriter}, target::TargetSet,
 };
 
+pub mod blocklist;
+pub mod checkpoint;
 pub mod cookie;
-pub mod lcg;
+pub mod cyclic;
+pub mod dedup;
 pub mod logger;
 pub mod packet_receiver;
 pub mod packet_sender;
+pub mod probe;
 pub mod output;
+pub mod rate_limiter;
 pub mod network_data;
+pub mod target;
 
 pub struct Scanner {
     packet_sender: PacketSender,
@@ -24,65 +41,166 @@ pub struct Scanner {
     packet_receiver_control_tx: ControlTx, 
     logger: Logger,
     logger_control_tx: ControlTx,
-    output: Box<dyn Output + Send>,
-    output_control_tx: ControlTx
+    output: OutputWriter,
+    output_control_tx: ControlTx,
+    cooldown: Duration,
+    run_state: SharedRunState,
+}
+
+/// What to scan, every random parameter of the scan is derived from `seed`
+pub struct ScanConfig {
+    pub seed: u64,
+    /// Source ports of the probes, part of the cookies like the seed
+    pub source_ports: RangeInclusive<u16>,
+    /// `checkpoint::fingerprint` of the scan, saved in its checkpoints
+    pub fingerprint: u64,
+    pub probe: ProbeType,
+    pub tcp_options: TcpOptions,
+    /// Time to live of the probes
+    pub ttl: u8,
+    /// Copies of the probe sent to each target, `probe_delay` apart
+    pub probes: u32,
+    pub probe_delay: Duration,
+    pub shard: u32,
+    pub shards: u32,
+    pub ports: Vec<u16>,
+    pub targets: TargetSet,
+    pub blocklist: Blocklist,
+    /// Packets per second
+    pub rate: Option<u64>,
+    /// Bits per second on the wire
+    pub bandwidth: Option<u64>,
+    /// Also output the ports found closed
+    pub report_closed: bool,
+    /// Time replies are still collected for once everything is sent
+    pub cooldown: Duration,
+    /// File the progress is saved to every `checkpoint_interval`
+    pub checkpoint: Option<String>,
+    pub checkpoint_interval: Duration,
+    /// Progress of the scan being resumed, its seed, shard and fingerprint must match the ones above
+    pub resume: Option<Checkpoint>,
 }
 
 pub enum ThreadControlMessage {
     Die, 
+    Cooldown(Duration),
 }
 
 pub type ControlTx = Sender<ThreadControlMessage>; 
 pub type ControlRx = Receiver<ThreadControlMessage>;
 
 impl Scanner {
-    pub(crate) fn new(port: u16, interface_data: InterfaceData, run_state: SharedRunState, output: Box<dyn Output + Send>, output_handle: OutputHandle) -> Self {
-        let cookie_hasher = CookieHasher::new();
+    pub(crate) fn new(config: ScanConfig, interface_data: InterfaceData, run_state: SharedRunState, output: OutputWriter, output_handle: OutputHandle) -> Self {
+        // A named generator rather than StdRng, whose algorithm may change
+        // between rand versions, so a seed always gives the same scan
+        let mut rng = ChaCha8Rng::seed_from_u64(config.seed);
+        let cookie_hasher = CookieHasher::new(&mut rng, config.source_ports.clone());
+
+        let seen = Dedup::new(&config.targets, &config.ports);
+        let mut ipv4_iterator = IPv4Iterator::new(
+            config.ports.clone(),
+            config.targets,
+            config.shard,
+            config.shards,
+            &mut rng,
+        );
+        let estimated_len = ipv4_iterator.estimated_len();
 
-        let (logger, stats, logger_control_tx) = Logger::new(run_state.clone());
+        let (stats, resumed) = match config.resume {
+            Some(checkpoint) => {
+                ipv4_iterator.advance(checkpoint.stats.position);
+                (checkpoint.stats, checkpoint.elapsed)
+            }
+            None => (Stats::new(), Duration::ZERO),
+        };
+
+        let retry_tail = config.probe_delay * config.probes.saturating_sub(1);
+        let checkpointer = config.checkpoint.map(|path| {
+            let checkpoint = Checkpoint {
+                seed: config.seed,
+                source_ports: config.source_ports.clone(),
+                shard: config.shard,
+                shards: config.shards,
+                fingerprint: config.fingerprint,
+                elapsed: resumed,
+                stats: stats.clone(),
+                output_offset: 0,
+            };
+            Checkpointer::new(
+                path,
+                config.checkpoint_interval,
+                config.cooldown + retry_tail,
+                checkpoint,
+                output_handle.offset.clone(),
+            )
+        });
+
+        let (logger, stats, logger_control_tx) = Logger::new(run_state.clone(), estimated_len, retry_tail, stats, resumed, checkpointer);
+
+        let probe = config.probe.build(config.tcp_options);
 
         let packet_sender = PacketSender::new(
-            cookie_hasher.clone(),
-            port,
-            interface_data.clone(),
+            PacketBuilder::new(probe.clone(), cookie_hasher.clone(), interface_data.clone(), config.ttl),
+            ipv4_iterator,
+            RetryQueue::new(config.probes, config.probe_delay),
+            config.blocklist,
+            RateLimiter::new(config.rate, config.bandwidth),
             stats.clone(),
             run_state.clone(),
         );
 
+        let validator = Validator::new(
+            probe,
+            config.probe,
+            cookie_hasher,
+            interface_data.device_ip,
+            config.ports,
+            config.report_closed,
+        );
+
         let (packet_receiver, packet_receiver_control_tx) = PacketReceiver::new(
-            cookie_hasher.clone(),
-            port,
+            validator,
+            seen,
             interface_data.clone(),
             stats.clone(),
-            run_state,
+            run_state.clone(),
             output_handle.out_tx, 
         );
 
 
-        let scanner = Scanner {
+        Scanner {
             packet_sender,
             packet_receiver_control_tx, 
             packet_receiver,
             logger,
             logger_control_tx,
             output,
-            output_control_tx: output_handle.control_tx
-        };
-        
-        scanner
+            output_control_tx: output_handle.control_tx,
+            cooldown: config.cooldown,
+            run_state,
+        }
     }
     pub(crate) fn scan(mut self) {
-        let output_handle = thread::spawn(move || self.output.output()); 
+        let output_handle = thread::spawn(move || self.output.run()); 
         let receiver_handle = thread::spawn(move || self.packet_receiver.receive());
         let sender_handle = thread::spawn(move || self.packet_sender.send());
         let logger_handle = thread::spawn(move || self.logger.log());
 
         sender_handle.join().unwrap();
+        self.logger_control_tx.send(ThreadControlMessage::Cooldown(self.cooldown)).unwrap();
+        let mut cooldown_left = self.cooldown;
+        while !cooldown_left.is_zero() {
+            self.run_state.act_state();
+            let step = cooldown_left.min(Duration::from_millis(100));
+            thread::sleep(step);
+            cooldown_left -= step;
+        }
         self.packet_receiver_control_tx.send(ThreadControlMessage::Die).unwrap();
         receiver_handle.join().unwrap();
-        self.logger_control_tx.send(ThreadControlMessage::Die).unwrap(); 
-        logger_handle.join().unwrap();
         self.output_control_tx.send(ThreadControlMessage::Die).unwrap(); 
         output_handle.join().unwrap();
+        // Last so the final checkpoint accounts for the whole output
+        self.logger_control_tx.send(ThreadControlMessage::Die).unwrap(); 
+        logger_handle.join().unwrap();
     }
 }
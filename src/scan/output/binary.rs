@@ -0,0 +1,325 @@
+use std::io::{self, Read, Write};
+use std::net::{Ipv4Addr, SocketAddrV4};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use clap::ValueEnum;
+
+use super::{Output, ScanInfo, ScanResult, Status};
+use crate::scan::probe::ProbeType;
+
+const MAGIC: &[u8; 4] = b"SINT";
+const VERSION: u8 = 1;
+
+/// Flags of the optional fields present in a record
+const HAS_WINDOW: u8 = 1;
+const HAS_TCP_OPTIONS: u8 = 1 << 1;
+const HAS_ICMP_CODE: u8 = 1 << 2;
+const HAS_ROUTER: u8 = 1 << 3;
+
+/// Compact format for large scans, every field is kept whatever `--fields`
+/// says and `read` picks them when converting back.
+///
+/// The file starts with `SINT`, the version, then the seed, the first and
+/// last source ports, the start of the scan in seconds, the probe name
+/// prefixed by its length in a byte and the ports prefixed by their count in
+/// 4 bytes. Each record is prefixed by its length in 2 bytes and holds the
+/// address, port, status, TTL, timestamp in milliseconds, flags of the
+/// optional fields and those fields. Numbers are big endian
+pub struct BinaryOut<W: Write> {
+    out: W,
+    info: ScanInfo,
+}
+
+impl<W: Write> BinaryOut<W> {
+    pub fn new(out: W, info: ScanInfo) -> Self {
+        Self { out, info }
+    }
+}
+
+impl<W: Write> Output for BinaryOut<W> {
+    fn header(&mut self) -> io::Result<usize> {
+        let mut header = MAGIC.to_vec();
+        header.push(VERSION);
+        header.extend(self.info.seed.to_be_bytes());
+        header.extend(self.info.source_ports.start().to_be_bytes());
+        header.extend(self.info.source_ports.end().to_be_bytes());
+        header.extend(unix_time(self.info.start).as_secs().to_be_bytes());
+        let probe = self.info.probe.to_string();
+        header.push(probe.len() as u8);
+        header.extend(probe.as_bytes());
+        header.extend((self.info.ports.len() as u32).to_be_bytes());
+        for port in &self.info.ports {
+            header.extend(port.to_be_bytes());
+        }
+        self.out.write_all(&header)?;
+        Ok(header.len())
+    }
+
+    fn write(&mut self, result: &ScanResult) -> io::Result<usize> {
+        let mut record = vec![0, 0];
+        record.extend(result.addr.ip().octets());
+        record.extend(result.addr.port().to_be_bytes());
+        record.push(status_code(result.status));
+        record.push(result.ttl);
+        record.extend((unix_time(result.timestamp).as_millis() as u64).to_be_bytes());
+
+        let flags_index = record.len();
+        let mut flags = 0;
+        record.push(0);
+        if let Some(window) = result.window {
+            flags |= HAS_WINDOW;
+            record.extend(window.to_be_bytes());
+        }
+        if let Some(options) = &result.tcp_options {
+            flags |= HAS_TCP_OPTIONS;
+            record.push(options.len() as u8);
+            record.extend(options);
+        }
+        if let Some(code) = result.icmp_code {
+            flags |= HAS_ICMP_CODE;
+            record.push(code);
+        }
+        if let Some(router) = result.router {
+            flags |= HAS_ROUTER;
+            record.extend(router.octets());
+        }
+        record[flags_index] = flags;
+
+        let len = (record.len() - 2) as u16;
+        record[..2].copy_from_slice(&len.to_be_bytes());
+        self.out.write_all(&record)?;
+        Ok(record.len())
+    }
+}
+
+/// Reads back what `BinaryOut` wrote
+pub struct BinaryReader<R: Read> {
+    input: R,
+    pub info: ScanInfo,
+}
+
+impl<R: Read> BinaryReader<R> {
+    /// Reads the header, fails on files of another format or version
+    pub fn new(mut input: R) -> io::Result<Self> {
+        let mut magic = [0u8; 4];
+        input.read_exact(&mut magic)?;
+        if &magic != MAGIC {
+            return Err(invalid("not a binary output"));
+        }
+        let version = read_array::<1>(&mut input)?[0];
+        if version != VERSION {
+            return Err(invalid(&format!("unsupported version {}", version)));
+        }
+
+        let seed = u64::from_be_bytes(read_array(&mut input)?);
+        let source_ports = u16::from_be_bytes(read_array(&mut input)?)
+            ..=u16::from_be_bytes(read_array(&mut input)?);
+        let start = UNIX_EPOCH + Duration::from_secs(u64::from_be_bytes(read_array(&mut input)?));
+
+        let mut probe = vec![0u8; read_array::<1>(&mut input)?[0] as usize];
+        input.read_exact(&mut probe)?;
+        let probe = String::from_utf8(probe)
+            .ok()
+            .and_then(|probe| ProbeType::from_str(&probe, false).ok())
+            .ok_or_else(|| invalid("unknown probe"))?;
+
+        let ports_len = u32::from_be_bytes(read_array(&mut input)?);
+        let mut ports = Vec::with_capacity(ports_len as usize);
+        for _ in 0..ports_len {
+            ports.push(u16::from_be_bytes(read_array(&mut input)?));
+        }
+
+        Ok(Self {
+            input,
+            info: ScanInfo {
+                seed,
+                source_ports,
+                probe,
+                ports,
+                start,
+            },
+        })
+    }
+
+    /// Next result, `None` at the end of the file
+    pub fn next_result(&mut self) -> io::Result<Option<ScanResult>> {
+        // Only an end of file before a record is clean, anywhere past its
+        // first byte the record was cut short
+        let mut len = [0u8; 2];
+        match self.input.read_exact(&mut len[..1]) {
+            Ok(()) => {}
+            Err(err) if err.kind() == io::ErrorKind::UnexpectedEof => return Ok(None),
+            Err(err) => return Err(err),
+        }
+        let mut record = vec![];
+        self.input
+            .read_exact(&mut len[1..])
+            .and_then(|()| {
+                record.resize(u16::from_be_bytes(len) as usize, 0);
+                self.input.read_exact(&mut record)
+            })
+            .map_err(|err| match err.kind() {
+                io::ErrorKind::UnexpectedEof => invalid("truncated record"),
+                _ => err,
+            })?;
+
+        self.parse_record(&record)
+            .map(Some)
+            .ok_or_else(|| invalid("truncated record"))
+    }
+
+    fn parse_record(&self, mut record: &[u8]) -> Option<ScanResult> {
+        let mut take = |len: usize| -> Option<&[u8]> {
+            let (taken, rest) = record.split_at_checked(len)?;
+            record = rest;
+            Some(taken)
+        };
+
+        let ip = Ipv4Addr::from(<[u8; 4]>::try_from(take(4)?).ok()?);
+        let port = u16::from_be_bytes(take(2)?.try_into().ok()?);
+        let mut result =
+            ScanResult::new(SocketAddrV4::new(ip, port), status_from_code(take(1)?[0])?);
+        result.probe = Some(self.info.probe);
+        result.ttl = take(1)?[0];
+        result.timestamp =
+            UNIX_EPOCH + Duration::from_millis(u64::from_be_bytes(take(8)?.try_into().ok()?));
+
+        let flags = take(1)?[0];
+        if flags & HAS_WINDOW != 0 {
+            result.window = Some(u16::from_be_bytes(take(2)?.try_into().ok()?));
+        }
+        if flags & HAS_TCP_OPTIONS != 0 {
+            let len = take(1)?[0] as usize;
+            result.tcp_options = Some(take(len)?.to_vec());
+        }
+        if flags & HAS_ICMP_CODE != 0 {
+            result.icmp_code = Some(take(1)?[0]);
+        }
+        if flags & HAS_ROUTER != 0 {
+            result.router = Some(Ipv4Addr::from(<[u8; 4]>::try_from(take(4)?).ok()?));
+        }
+        Some(result)
+    }
+}
+
+fn read_array<const N: usize>(input: &mut impl Read) -> io::Result<[u8; N]> {
+    let mut bytes = [0u8; N];
+    input.read_exact(&mut bytes)?;
+    Ok(bytes)
+}
+
+fn invalid(message: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message)
+}
+
+fn unix_time(time: SystemTime) -> Duration {
+    time.duration_since(UNIX_EPOCH).unwrap_or_default()
+}
+
+fn status_code(status: Status) -> u8 {
+    match status {
+        Status::Open => 0,
+        Status::Closed => 1,
+        Status::Filtered => 2,
+        Status::Up => 3,
+        Status::Unfiltered => 4,
+    }
+}
+
+fn status_from_code(code: u8) -> Option<Status> {
+    match code {
+        0 => Some(Status::Open),
+        1 => Some(Status::Closed),
+        2 => Some(Status::Filtered),
+        3 => Some(Status::Up),
+        4 => Some(Status::Unfiltered),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn info() -> ScanInfo {
+        ScanInfo {
+            seed: 0x0123_4567_89ab_cdef,
+            source_ports: 32768..=60999,
+            probe: ProbeType::TcpSyn,
+            ports: vec![22, 80, 443],
+            start: UNIX_EPOCH + Duration::from_secs(1_700_000_000),
+        }
+    }
+
+    fn results() -> Vec<ScanResult> {
+        let mut open = ScanResult::new("192.0.2.1:443".parse().unwrap(), Status::Open);
+        open.ttl = 57;
+        open.window = Some(64240);
+        open.tcp_options = Some(vec![2, 4, 5, 180, 4, 2]);
+        open.timestamp = UNIX_EPOCH + Duration::from_millis(1_700_000_000_123);
+
+        let mut filtered = ScanResult::new("192.0.2.2:22".parse().unwrap(), Status::Filtered);
+        filtered.ttl = 250;
+        filtered.icmp_code = Some(13);
+        filtered.router = Some(Ipv4Addr::new(198, 51, 100, 1));
+        filtered.timestamp = UNIX_EPOCH + Duration::from_millis(1_700_000_001_000);
+
+        vec![open, filtered]
+    }
+
+    fn write() -> Vec<u8> {
+        let mut binary = BinaryOut::new(vec![], info());
+        let mut len = binary.header().unwrap();
+        for result in results() {
+            len += binary.write(&result).unwrap();
+        }
+        assert_eq!(len, binary.out.len());
+        binary.out
+    }
+
+    #[test]
+    fn reads_back_what_was_written() {
+        let bytes = write();
+        let mut reader = BinaryReader::new(&bytes[..]).unwrap();
+
+        let info = info();
+        assert_eq!(reader.info.seed, info.seed);
+        assert_eq!(reader.info.source_ports, info.source_ports);
+        assert_eq!(reader.info.probe, info.probe);
+        assert_eq!(reader.info.ports, info.ports);
+        assert_eq!(reader.info.start, info.start);
+
+        for expected in results() {
+            let result = reader.next_result().unwrap().unwrap();
+            assert_eq!(result.addr, expected.addr);
+            assert_eq!(result.status, expected.status);
+            assert_eq!(result.probe, Some(ProbeType::TcpSyn));
+            assert_eq!(result.ttl, expected.ttl);
+            assert_eq!(result.window, expected.window);
+            assert_eq!(result.tcp_options, expected.tcp_options);
+            assert_eq!(result.timestamp, expected.timestamp);
+            assert_eq!(result.icmp_code, expected.icmp_code);
+            assert_eq!(result.router, expected.router);
+        }
+        assert!(reader.next_result().unwrap().is_none());
+    }
+
+    #[test]
+    fn rejects_other_files_and_truncated_records() {
+        assert!(BinaryReader::new(&b"text"[..]).is_err());
+        let mut other_version = write();
+        other_version[4] = VERSION + 1;
+        assert!(BinaryReader::new(&other_version[..]).is_err());
+
+        // Cut in the length prefix, then in the record itself
+        let bytes = write();
+        let mut first = BinaryOut::new(vec![], info());
+        let last_record = first.header().unwrap() + first.write(&results()[0]).unwrap();
+        for end in [last_record + 1, bytes.len() - 1] {
+            let mut reader = BinaryReader::new(&bytes[..end]).unwrap();
+            assert!(reader.next_result().unwrap().is_some());
+            let err = reader.next_result().unwrap_err();
+            assert_eq!(err.kind(), io::ErrorKind::InvalidData, "{}", end);
+        }
+    }
+}
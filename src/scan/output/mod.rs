@@ -0,0 +1,572 @@
+use std::fmt;
+use std::io::{self, Seek, SeekFrom, Write};
+use std::net::{Ipv4Addr, SocketAddrV4};
+use std::ops::RangeInclusive;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::mpsc::{channel, Receiver, Sender};
+use std::sync::Arc;
+
+use std::fs;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use clap::ValueEnum;
+
+use super::probe::ProbeType;
+use super::{ControlRx, ControlTx};
+use binary::BinaryOut;
+
+pub mod binary;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Status {
+    /// Answered with a SYN-ACK, a RST with a non zero window, or any UDP reply
+    Open,
+    /// Answered with a RST, or an ICMP port unreachable for UDP
+    Closed,
+    /// A router answered with an ICMP destination unreachable
+    Filtered,
+    /// Answered an ICMP echo request
+    Up,
+    /// Answered an ACK probe with a RST, no firewall drops the traffic
+    Unfiltered,
+}
+
+impl fmt::Display for Status {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Status::Open => write!(f, "open"),
+            Status::Closed => write!(f, "closed"),
+            Status::Filtered => write!(f, "filtered"),
+            Status::Up => write!(f, "up"),
+            Status::Unfiltered => write!(f, "unfiltered"),
+        }
+    }
+}
+
+/// Everything known about a reply, sinks write the fields they are asked for
+#[derive(Clone, Debug)]
+pub struct ScanResult {
+    pub addr: SocketAddrV4,
+    pub status: Status,
+    /// Probe answered
+    pub probe: Option<ProbeType>,
+    /// TTL of the reply as it reached us
+    pub ttl: u8,
+    /// Window and raw options of TCP replies
+    pub window: Option<u16>,
+    pub tcp_options: Option<Vec<u8>>,
+    /// Time the reply was received
+    pub timestamp: SystemTime,
+    /// Code of the ICMP message and the router that sent it, for filtered targets
+    pub icmp_code: Option<u8>,
+    pub router: Option<Ipv4Addr>,
+}
+
+impl ScanResult {
+    /// The probe and TTL are filled in by the receiver
+    pub fn new(addr: SocketAddrV4, status: Status) -> Self {
+        Self {
+            addr,
+            status,
+            probe: None,
+            ttl: 0,
+            window: None,
+            tcp_options: None,
+            timestamp: SystemTime::now(),
+            icmp_code: None,
+            router: None,
+        }
+    }
+
+    /// Text form of `field`, `None` when the result doesn't have it
+    pub fn field(&self, field: Field) -> Option<String> {
+        match field {
+            Field::Ip => Some(self.addr.ip().to_string()),
+            Field::Port => Some(self.addr.port().to_string()),
+            Field::Status => Some(self.status.to_string()),
+            Field::Probe => self.probe.map(|probe| probe.to_string()),
+            Field::Ttl => Some(self.ttl.to_string()),
+            Field::Window => self.window.map(|window| window.to_string()),
+            Field::TcpOptions => self.tcp_options.as_deref().map(format_tcp_options),
+            Field::Timestamp => {
+                let since_epoch = self.timestamp.duration_since(UNIX_EPOCH).unwrap_or_default();
+                Some(format!("{}.{:03}", since_epoch.as_secs(), since_epoch.subsec_millis()))
+            }
+            Field::IcmpCode => self.icmp_code.map(|code| code.to_string()),
+            Field::Router => self.router.map(|router| router.to_string()),
+        }
+    }
+}
+
+/// Lists TCP options by name in the order they were sent, e.g.
+/// `mss=1460,sackok,ts,nop,ws=7`
+fn format_tcp_options(mut options: &[u8]) -> String {
+    let mut names = vec![];
+    while let Some(&kind) = options.first() {
+        let len = match kind {
+            0 | 1 => 1,
+            _ => match options.get(1) {
+                Some(&len) if len >= 2 && len as usize <= options.len() => len as usize,
+                _ => break,
+            },
+        };
+        let data = &options[2.min(len)..len];
+        names.push(match (kind, data) {
+            (0, _) => "eol".to_string(),
+            (1, _) => "nop".to_string(),
+            (2, [a, b]) => format!("mss={}", u16::from_be_bytes([*a, *b])),
+            (3, [shift]) => format!("ws={}", shift),
+            (4, _) => "sackok".to_string(),
+            (5, _) => "sack".to_string(),
+            (8, _) => "ts".to_string(),
+            _ => format!("opt{}", kind),
+        });
+        options = &options[len..];
+    }
+    names.join(",")
+}
+
+/// Fields of a result a sink can write
+#[derive(Clone, Copy, Debug, PartialEq, Eq, ValueEnum)]
+pub enum Field {
+    Ip,
+    Port,
+    Status,
+    Probe,
+    Ttl,
+    Window,
+    TcpOptions,
+    Timestamp,
+    IcmpCode,
+    Router,
+}
+
+impl Field {
+    /// Name of the field in formats labelling them
+    pub fn key(&self) -> &'static str {
+        match self {
+            Field::Ip => "ip",
+            Field::Port => "port",
+            Field::Status => "status",
+            Field::Probe => "probe",
+            Field::Ttl => "ttl",
+            Field::Window => "window",
+            Field::TcpOptions => "tcp_options",
+            Field::Timestamp => "timestamp",
+            Field::IcmpCode => "icmp_code",
+            Field::Router => "router",
+        }
+    }
+
+    fn is_numeric(&self) -> bool {
+        matches!(
+            self,
+            Field::Port | Field::Ttl | Field::Window | Field::Timestamp | Field::IcmpCode
+        )
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, ValueEnum)]
+pub enum OutputFormat {
+    /// One line of space separated fields per result
+    Text,
+    /// One JSON object per line
+    Ndjson,
+    /// Comma separated values with a header row
+    Csv,
+    /// Length prefixed records after a header describing the scan
+    Binary,
+}
+
+/// What the scan was, kept in the header of the formats that store it
+#[derive(Clone, Debug)]
+pub struct ScanInfo {
+    pub seed: u64,
+    pub source_ports: RangeInclusive<u16>,
+    pub probe: ProbeType,
+    pub ports: Vec<u16>,
+    pub start: SystemTime,
+}
+
+impl OutputFormat {
+    /// Fields written when none are selected
+    pub fn default_fields(&self) -> Vec<Field> {
+        match self {
+            OutputFormat::Text => vec![
+                Field::Ip,
+                Field::Port,
+                Field::Status,
+                Field::IcmpCode,
+                Field::Router,
+            ],
+            OutputFormat::Ndjson => vec![
+                Field::Ip,
+                Field::Port,
+                Field::Status,
+                Field::Ttl,
+                Field::Window,
+                Field::Timestamp,
+                Field::Probe,
+            ],
+            OutputFormat::Csv | OutputFormat::Binary => vec![
+                Field::Ip,
+                Field::Port,
+                Field::Status,
+                Field::Probe,
+                Field::Ttl,
+                Field::Window,
+                Field::TcpOptions,
+                Field::Timestamp,
+                Field::IcmpCode,
+                Field::Router,
+            ],
+        }
+    }
+
+    /// The binary format keeps every field along with `info`, the others
+    /// write `fields`
+    pub fn build<W: Write + Send + 'static>(
+        &self,
+        out: W,
+        fields: Vec<Field>,
+        info: ScanInfo,
+    ) -> Box<dyn Output + Send> {
+        match self {
+            OutputFormat::Text => Box::new(TextOut::new(out, fields)),
+            OutputFormat::Ndjson => Box::new(NdjsonOut::new(out, fields)),
+            OutputFormat::Csv => Box::new(CsvOut::new(out, fields)),
+            OutputFormat::Binary => Box::new(BinaryOut::new(out, info)),
+        }
+    }
+}
+
+pub type OutTx = Sender<ScanResult>;
+pub type OutRx = Receiver<ScanResult>;
+/// Bytes written by an output so far
+pub type OutputOffset = Arc<AtomicU64>;
+
+/// Sink the results are written to, one at a time
+pub trait Output {
+    /// Writes whatever comes before the results in a new output, returns the
+    /// number of bytes written
+    fn header(&mut self) -> io::Result<usize> {
+        Ok(0)
+    }
+
+    /// Writes `result`, returns the number of bytes written
+    fn write(&mut self, result: &ScanResult) -> io::Result<usize>;
+}
+
+pub struct OutputHandle {
+    pub out_tx: OutTx,
+    pub control_tx: ControlTx,
+    pub offset: OutputOffset,
+}
+
+/// Opens the output file, when resuming it is cut at `resume_offset` and appended to
+pub fn open_file(path: &str, resume_offset: Option<u64>) -> fs::File {
+    let file_handle = match resume_offset {
+        Some(offset) => fs::OpenOptions::new()
+            .write(true)
+            .open(path)
+            .and_then(|mut file| {
+                file.set_len(offset)?;
+                file.seek(SeekFrom::Start(offset))?;
+                Ok(file)
+            }),
+        None => fs::OpenOptions::new()
+            .create(true)
+            .write(true)
+            .truncate(true)
+            .open(path),
+    };
+    match file_handle {
+        Ok(file) => file,
+        Err(err) => panic!("Failed opening file: {}", err),
+    }
+}
+
+/// Passes the results the receiver sends on to an `Output`, counting the bytes written
+pub struct OutputWriter {
+    output: Box<dyn Output + Send>,
+    out_rx: OutRx,
+    control_rx: ControlRx,
+    offset: OutputOffset,
+}
+
+impl OutputWriter {
+    /// `resume_offset` is the size of the output already written by the scan
+    /// being resumed, the header is only written to new outputs
+    pub fn new(mut output: Box<dyn Output + Send>, resume_offset: Option<u64>) -> (Self, OutputHandle) {
+        let written = match resume_offset {
+            Some(offset) => offset,
+            None => output.header().unwrap() as u64,
+        };
+
+        let (out_tx, out_rx) = channel();
+        let (control_tx, control_rx) = channel();
+        let offset = Arc::new(AtomicU64::new(written));
+
+        let output_handle = OutputHandle {
+            out_tx,
+            control_tx,
+            offset: offset.clone(),
+        };
+
+        (
+            OutputWriter {
+                output,
+                out_rx,
+                control_rx,
+                offset,
+            },
+            output_handle,
+        )
+    }
+
+    fn write(&mut self, result: ScanResult) {
+        let len = self.output.write(&result).unwrap();
+        self.offset.fetch_add(len as u64, Ordering::SeqCst);
+    }
+
+    pub fn run(&mut self) {
+        loop {
+            if self.control_rx.try_recv().is_ok() {
+                while let Ok(result) = self.out_rx.try_recv() {
+                    self.write(result);
+                }
+                return;
+            }
+            if let Ok(result) = self.out_rx.recv_timeout(Duration::new(1, 0)) {
+                self.write(result);
+            }
+        }
+    }
+}
+
+/// One line per result with the selected fields separated by spaces, fields
+/// the result doesn't have are written as `-` so the columns stay aligned
+pub struct TextOut<W: Write> {
+    out: W,
+    fields: Vec<Field>,
+}
+
+impl<W: Write> TextOut<W> {
+    pub fn new(out: W, fields: Vec<Field>) -> Self {
+        Self { out, fields }
+    }
+}
+
+impl<W: Write> Output for TextOut<W> {
+    fn write(&mut self, result: &ScanResult) -> io::Result<usize> {
+        let mut line = self
+            .fields
+            .iter()
+            .map(|field| result.field(*field).unwrap_or_else(|| "-".to_string()))
+            .collect::<Vec<_>>()
+            .join(" ");
+        line += "\n";
+        self.out.write_all(line.as_bytes())?;
+        Ok(line.len())
+    }
+}
+
+/// One JSON object per line, numbers are written as such and fields the
+/// result doesn't have are left out
+pub struct NdjsonOut<W: Write> {
+    out: W,
+    fields: Vec<Field>,
+}
+
+impl<W: Write> NdjsonOut<W> {
+    pub fn new(out: W, fields: Vec<Field>) -> Self {
+        Self { out, fields }
+    }
+}
+
+impl<W: Write> Output for NdjsonOut<W> {
+    fn write(&mut self, result: &ScanResult) -> io::Result<usize> {
+        let members = self
+            .fields
+            .iter()
+            .filter_map(|field| {
+                let value = result.field(*field)?;
+                let value = if field.is_numeric() {
+                    value
+                } else {
+                    json_string(&value)
+                };
+                Some(format!("\"{}\":{}", field.key(), value))
+            })
+            .collect::<Vec<_>>();
+        let line = format!("{{{}}}\n", members.join(","));
+        self.out.write_all(line.as_bytes())?;
+        Ok(line.len())
+    }
+}
+
+/// Quotes `value` as a JSON string, escaping quotes, backslashes and control characters
+fn json_string(value: &str) -> String {
+    let mut quoted = String::from('"');
+    for c in value.chars() {
+        match c {
+            '"' => quoted += "\\\"",
+            '\\' => quoted += "\\\\",
+            c if c.is_control() => quoted += &format!("\\u{:04x}", c as u32),
+            c => quoted.push(c),
+        }
+    }
+    quoted.push('"');
+    quoted
+}
+
+/// RFC 4180 comma separated values, the header names the selected fields and
+/// fields the result doesn't have are left empty
+pub struct CsvOut<W: Write> {
+    out: W,
+    fields: Vec<Field>,
+}
+
+impl<W: Write> CsvOut<W> {
+    pub fn new(out: W, fields: Vec<Field>) -> Self {
+        Self { out, fields }
+    }
+
+    fn write_record(&mut self, record: Vec<String>) -> io::Result<usize> {
+        let line = record
+            .iter()
+            .map(|value| csv_value(value))
+            .collect::<Vec<_>>()
+            .join(",")
+            + "\r\n";
+        self.out.write_all(line.as_bytes())?;
+        Ok(line.len())
+    }
+}
+
+impl<W: Write> Output for CsvOut<W> {
+    fn header(&mut self) -> io::Result<usize> {
+        let record = self.fields.iter().map(|field| field.key().to_string()).collect();
+        self.write_record(record)
+    }
+
+    fn write(&mut self, result: &ScanResult) -> io::Result<usize> {
+        let record = self
+            .fields
+            .iter()
+            .map(|field| result.field(*field).unwrap_or_default())
+            .collect();
+        self.write_record(record)
+    }
+}
+
+/// Quotes values holding a separator, a quote or a line break, doubling the quotes
+fn csv_value(value: &str) -> String {
+    if value.contains([',', '"', '\r', '\n']) {
+        format!("\"{}\"", value.replace('"', "\"\""))
+    } else {
+        value.to_string()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn result() -> ScanResult {
+        let mut result = ScanResult::new("192.0.2.1:80".parse().unwrap(), Status::Open);
+        result.probe = Some(ProbeType::TcpSyn);
+        result.ttl = 57;
+        result.timestamp = UNIX_EPOCH + Duration::from_millis(1_700_000_000_123);
+        result
+    }
+
+    #[test]
+    fn fields_in_text_form() {
+        let mut result = result();
+        result.tcp_options = Some(vec![2, 4, 5, 180, 4, 2, 1, 3, 3, 7]);
+
+        assert_eq!(result.field(Field::Probe).as_deref(), Some("tcp-syn"));
+        assert_eq!(
+            result.field(Field::Timestamp).as_deref(),
+            Some("1700000000.123")
+        );
+        assert_eq!(
+            result.field(Field::TcpOptions).as_deref(),
+            Some("mss=1460,sackok,nop,ws=7")
+        );
+        assert_eq!(result.field(Field::Router), None);
+    }
+
+    #[test]
+    fn text_keeps_columns_of_absent_fields() {
+        let mut filtered = result();
+        filtered.status = Status::Filtered;
+        filtered.icmp_code = Some(13);
+        filtered.router = Some(Ipv4Addr::new(198, 51, 100, 1));
+
+        let fields = OutputFormat::Text.default_fields();
+        let mut text = TextOut::new(vec![], fields);
+        let len = text.write(&result()).unwrap() + text.write(&filtered).unwrap();
+
+        let out = String::from_utf8(text.out).unwrap();
+        assert_eq!(
+            out,
+            "192.0.2.1 80 open - -\n192.0.2.1 80 filtered 13 198.51.100.1\n"
+        );
+        assert_eq!(len, out.len());
+    }
+
+    #[test]
+    fn json_strings_are_escaped() {
+        assert_eq!(json_string("open"), "\"open\"");
+        assert_eq!(json_string("a\"b\\c"), "\"a\\\"b\\\\c\"");
+        assert_eq!(json_string("\n\t\u{7f}"), "\"\\u000a\\u0009\\u007f\"");
+        assert_eq!(json_string("é"), "\"é\"");
+    }
+
+    #[test]
+    fn ndjson_writes_numbers_bare_and_skips_absent_fields() {
+        let fields = vec![
+            Field::Ip,
+            Field::Port,
+            Field::Probe,
+            Field::Window,
+            Field::Timestamp,
+        ];
+        let mut ndjson = NdjsonOut::new(vec![], fields);
+        let len = ndjson.write(&result()).unwrap();
+
+        let out = String::from_utf8(ndjson.out).unwrap();
+        assert_eq!(
+            out,
+            "{\"ip\":\"192.0.2.1\",\"port\":80,\"probe\":\"tcp-syn\",\"timestamp\":1700000000.123}\n"
+        );
+        assert_eq!(len, out.len());
+    }
+
+    #[test]
+    fn csv_values_are_quoted_when_needed() {
+        assert_eq!(csv_value("mss=1460"), "mss=1460");
+        assert_eq!(csv_value("mss=1460,sackok"), "\"mss=1460,sackok\"");
+        assert_eq!(csv_value("say \"hi\""), "\"say \"\"hi\"\"\"");
+        assert_eq!(csv_value("a\nb"), "\"a\nb\"");
+        assert_eq!(csv_value(""), "");
+    }
+
+    #[test]
+    fn csv_has_a_header_and_empty_absent_fields() {
+        let fields = vec![Field::Ip, Field::Window, Field::TcpOptions];
+        let mut csv = CsvOut::new(vec![], fields);
+        let mut result = result();
+        result.tcp_options = Some(vec![2, 4, 5, 180, 4, 2]);
+        let len = csv.header().unwrap() + csv.write(&result).unwrap();
+
+        let out = String::from_utf8(csv.out).unwrap();
+        assert_eq!(
+            out,
+            "ip,window,tcp_options\r\n192.0.2.1,,\"mss=1460,sackok\"\r\n"
+        );
+        assert_eq!(len, out.len());
+    }
+}
@@ -1,25 +1,129 @@
-use std::net::IpAddr;
+use std::net::Ipv4Addr;
 use std::sync::mpsc::channel;
+use std::sync::Arc;
 
 use crate::SharedRunState;
 
-use super::output::OutTx;
-use super::{cookie::CookieHasher, logger::LoggerStats, network_data::InterfaceData};
+use super::output::{OutTx, ScanResult, Status};
+use super::{
+    cookie::CookieHasher, dedup::Dedup, logger::LoggerStats, network_data::InterfaceData,
+    probe::{Probe, ProbeType},
+};
 use super::{ControlRx, ControlTx};
 
-use pnet_packet::{ip::IpNextHeaderProtocols, tcp::TcpFlags};
+use pnet_packet::ethernet::{EtherTypes, EthernetPacket};
+use pnet_packet::icmp::destination_unreachable::DestinationUnreachablePacket;
+use pnet_packet::icmp::{IcmpPacket, IcmpTypes};
+use pnet_packet::ipv4::Ipv4Packet;
+use pnet_packet::{ip::IpNextHeaderProtocols, Packet};
 
+use std::io::ErrorKind;
 use std::time::Duration;
 
-use pnet::transport;
-use pnet::transport::TransportChannelType::Layer4;
-use pnet::transport::TransportProtocol::Ipv4;
+use pnet::datalink;
 
-pub(crate) struct PacketReceiver {
-    layer_receiver: transport::TransportReceiver,
+/// Checks that replies answer one of our probes and classifies them
+pub(crate) struct Validator {
+    probe: Arc<dyn Probe>,
+    probe_type: ProbeType,
     cookie_hasher: CookieHasher,
-    interface_data: InterfaceData,
-    port: u16,
+    device_ip: Ipv4Addr,
+    ports: Vec<u16>,
+    report_closed: bool,
+}
+
+impl Validator {
+    pub fn new(
+        probe: Arc<dyn Probe>,
+        probe_type: ProbeType,
+        cookie_hasher: CookieHasher,
+        device_ip: Ipv4Addr,
+        ports: Vec<u16>,
+        report_closed: bool,
+    ) -> Self {
+        Self {
+            probe,
+            probe_type,
+            cookie_hasher,
+            device_ip,
+            ports,
+            report_closed,
+        }
+    }
+
+    fn parse_frame(&self, frame: &[u8]) -> Option<ScanResult> {
+        let ethernet = EthernetPacket::new(frame)?;
+        if ethernet.get_ethertype() != EtherTypes::Ipv4 {
+            return None;
+        }
+
+        let ip = Ipv4Packet::new(ethernet.payload())?;
+        if ip.get_destination() != self.device_ip {
+            return None;
+        }
+
+        let unreachable = ip.get_next_level_protocol() == IpNextHeaderProtocols::Icmp
+            && IcmpPacket::new(ip.payload())?.get_icmp_type() == IcmpTypes::DestinationUnreachable;
+
+        let mut result = if unreachable {
+            self.parse_unreachable(&ip)?
+        } else if ip.get_next_level_protocol() == self.probe.protocol() {
+            self.probe.classify(&ip, &self.cookie_hasher)?
+        } else {
+            return None;
+        };
+
+        if self.ports.binary_search(&result.addr.port()).is_err()
+            || (result.status == Status::Closed && !self.report_closed)
+        {
+            return None;
+        }
+        result.probe = Some(self.probe_type);
+        result.ttl = ip.get_ttl();
+        Some(result)
+    }
+
+    /// Destination unreachable messages quote the IP header and the first
+    /// 8 bytes of our probe, enough for its ports and sequence number, on top
+    /// of which the quoted IP identification must match the cookie
+    fn parse_unreachable(&self, ip: &Ipv4Packet) -> Option<ScanResult> {
+        let unreachable = DestinationUnreachablePacket::new(ip.payload())?;
+        let quoted_ip = Ipv4Packet::new(unreachable.payload())?;
+        if quoted_ip.get_source() != self.device_ip
+            || quoted_ip.get_next_level_protocol() != self.probe.protocol()
+        {
+            return None;
+        }
+
+        let quoted = unreachable
+            .payload()
+            .get(quoted_ip.get_header_length() as usize * 4..)?;
+        if quoted.len() < 8 {
+            return None;
+        }
+
+        let code = unreachable.get_icmp_code().0;
+        let mut result =
+            self.probe
+                .classify_unreachable(&quoted_ip, quoted, code, &self.cookie_hasher)?;
+        let cookie =
+            self.cookie_hasher
+                .get_cookie(self.device_ip, *result.addr.ip(), result.addr.port());
+        if cookie.ip_id != quoted_ip.get_identification() {
+            return None;
+        }
+        result.icmp_code = Some(code);
+        result.router = Some(ip.get_source());
+        Some(result)
+    }
+}
+
+pub(crate) struct PacketReceiver {
+    channel: Box<dyn datalink::DataLinkReceiver>,
+    validator: Validator,
+    /// Results already reported, targets answer every copy of the probe and
+    /// some retransmit their replies
+    seen: Dedup,
     stats: LoggerStats,
     run_state: SharedRunState,
     control_rx: ControlRx,
@@ -28,23 +132,37 @@ pub(crate) struct PacketReceiver {
 
 impl PacketReceiver {
     pub fn new(
-        cookie_hasher: CookieHasher,
-        port: u16,
+        validator: Validator,
+        seen: Dedup,
         interface_data: InterfaceData,
         stats: LoggerStats,
         run_state: SharedRunState,
         out_tx: OutTx,
     ) -> (PacketReceiver, ControlTx) {
-        let (_tx, layer_receiver) =
-            transport::transport_channel(64553, Layer4(Ipv4(IpNextHeaderProtocols::Tcp))).unwrap();
+        // Listening on the link sees every protocol, so TCP replies and ICMP
+        // errors come through the same channel
+        let config = pnet::datalink::Config {
+            write_buffer_size: 4096,
+            read_buffer_size: 65536,
+            read_timeout: Some(Duration::new(1, 0)),
+            write_timeout: None,
+            channel_type: pnet_datalink::ChannelType::Layer2,
+            bpf_fd_attempts: 1000,
+            linux_fanout: None,
+            promiscuous: false,
+        };
+
+        let rx = match datalink::channel(&interface_data.iface, config).unwrap() {
+            pnet_datalink::Channel::Ethernet(_, receiver) => receiver,
+            _ => panic!(),
+        };
 
         let (control_tx, control_rx) = channel();
 
         let packet_receiver = PacketReceiver {
-            layer_receiver,
-            cookie_hasher,
-            interface_data,
-            port,
+            channel: rx,
+            validator,
+            seen,
             stats,
             run_state,
             control_rx,
@@ -55,49 +173,196 @@ impl PacketReceiver {
     }
 
     pub fn receive(&mut self) {
-        let mut packet_rcv: pnet_transport::TcpTransportChannelIterator<'_> =
-            pnet::transport::tcp_packet_iter(&mut self.layer_receiver);
-
         loop {
             self.run_state.act_state();
-            if let Ok(_) = self.control_rx.try_recv() {
+            if self.control_rx.try_recv().is_ok() {
                 return;
             }
-            match packet_rcv.next_with_timeout(Duration::new(5, 0)) {
-                Ok(res) => {
-                    if let Some((packet, addr)) = res {
-                        let src_port = packet.get_source();
-                        if !self.port == src_port {
+            match self.channel.next() {
+                Ok(frame) => {
+                    if let Some(result) = self.validator.parse_frame(frame) {
+                        if !self.seen.insert(result.addr) {
+                            self.stats.lock().unwrap().duplicates += 1;
                             continue;
                         }
-
-                        let src_ip = match addr {
-                            IpAddr::V4(ip) => ip,
-                            IpAddr::V6(_) => continue,
-                        };
-
-                        let dst_ip = self.interface_data.device_ip;
-                        let dst_port = packet.get_destination();
-
-                        let flags = packet.get_flags();
-
-                        if flags & TcpFlags::SYN == TcpFlags::SYN
-                            && flags & TcpFlags::ACK == TcpFlags::ACK
-                        {
-                            if self
-                                .cookie_hasher
-                                .check_port_cookie(dst_ip, src_ip, dst_port)
-                            {
-                                self.stats.lock().unwrap().received += 1;
-                                self.out_tx.send(src_ip).unwrap();
-                            } else {
-                                continue;
-                            }
-                        }
+                        self.stats.lock().unwrap().received += 1;
+                        self.out_tx.send(result).unwrap();
                     }
                 }
-                Err(err) => println!("{}", err),
+                Err(err) if err.kind() == ErrorKind::TimedOut => continue,
+                Err(err) => eprintln!("{}", err),
             }
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::scan::cookie::Cookie;
+    use crate::scan::probe::tcp::TcpOptions;
+    use pnet_packet::ipv4::MutableIpv4Packet;
+    use rand::SeedableRng;
+    use rand_chacha::ChaCha8Rng;
+    use std::net::SocketAddrV4;
+
+    const DEVICE: Ipv4Addr = Ipv4Addr::new(192, 0, 2, 1);
+    const TARGET: Ipv4Addr = Ipv4Addr::new(198, 51, 100, 7);
+    const ROUTER: Ipv4Addr = Ipv4Addr::new(203, 0, 113, 9);
+
+    fn validator() -> Validator {
+        let cookie_hasher = CookieHasher::new(&mut ChaCha8Rng::seed_from_u64(1), 32768..=60999);
+        Validator::new(
+            ProbeType::TcpSyn.build(TcpOptions::None),
+            ProbeType::TcpSyn,
+            cookie_hasher,
+            DEVICE,
+            vec![80, 443],
+            false,
+        )
+    }
+
+    fn cookie(validator: &Validator, port: u16) -> Cookie {
+        validator.cookie_hasher.get_cookie(DEVICE, TARGET, port)
+    }
+
+    /// Writes an IPv4 header of `header_words` 32 bit words at the start of `buffer`
+    fn write_ip(
+        buffer: &mut [u8],
+        header_words: u8,
+        total_length: u16,
+        source: Ipv4Addr,
+        destination: Ipv4Addr,
+    ) {
+        let mut ip = MutableIpv4Packet::new(buffer).unwrap();
+        ip.set_version(4);
+        ip.set_header_length(header_words);
+        ip.set_total_length(total_length);
+        ip.set_ttl(61);
+        ip.set_source(source);
+        ip.set_destination(destination);
+    }
+
+    /// Ethernet frame of an ICMP destination unreachable from the router,
+    /// quoting `quoted` after an IP header of `header_words` words from our probe
+    fn unreachable(ip_id: u16, header_words: u8, quoted: &[u8]) -> Vec<u8> {
+        let quoted_ip_len = header_words as usize * 4;
+        let icmp_len = 8 + quoted_ip_len + quoted.len();
+        let mut frame = vec![0u8; 14 + 20 + icmp_len];
+        frame[12..14].copy_from_slice(&[0x08, 0x00]);
+
+        write_ip(&mut frame[14..], 5, (20 + icmp_len) as u16, ROUTER, DEVICE);
+        frame[14 + 9] = IpNextHeaderProtocols::Icmp.0;
+
+        let icmp = &mut frame[34..];
+        icmp[0] = IcmpTypes::DestinationUnreachable.0;
+        icmp[1] = 13;
+
+        let quoted_ip = &mut icmp[8..];
+        write_ip(
+            quoted_ip,
+            header_words,
+            (quoted_ip_len + 20) as u16,
+            DEVICE,
+            TARGET,
+        );
+        quoted_ip[4..6].copy_from_slice(&ip_id.to_be_bytes());
+        quoted_ip[9] = IpNextHeaderProtocols::Tcp.0;
+        quoted_ip[quoted_ip_len..].copy_from_slice(quoted);
+        frame
+    }
+
+    /// Ports and sequence number, the first 8 bytes of a TCP probe
+    fn tcp_quote(source: u16, destination: u16, seq: u32) -> Vec<u8> {
+        [
+            &source.to_be_bytes()[..],
+            &destination.to_be_bytes(),
+            &seq.to_be_bytes(),
+        ]
+        .concat()
+    }
+
+    #[test]
+    fn unreachable_quoting_our_probe_is_filtered() {
+        let validator = validator();
+        let cookie = cookie(&validator, 443);
+        let quote = tcp_quote(cookie.port, 443, cookie.seq);
+
+        // Quoted headers with options move the probe further in
+        for header_words in [5, 6] {
+            let result = validator
+                .parse_frame(&unreachable(cookie.ip_id, header_words, &quote))
+                .unwrap();
+            assert_eq!(result.addr, SocketAddrV4::new(TARGET, 443));
+            assert_eq!(result.status, Status::Filtered);
+            assert_eq!(result.icmp_code, Some(13));
+            assert_eq!(result.router, Some(ROUTER));
+            assert_eq!(result.ttl, 61);
+        }
+
+        // Routers may quote more than the 8 bytes required
+        let long_quote = [&quote[..], &[0; 12]].concat();
+        assert!(validator
+            .parse_frame(&unreachable(cookie.ip_id, 5, &long_quote))
+            .is_some());
+    }
+
+    #[test]
+    fn unreachable_not_matching_the_cookie_is_dropped() {
+        let validator = validator();
+        let cookie = cookie(&validator, 443);
+
+        let frames = [
+            // Another IP identification
+            unreachable(
+                cookie.ip_id ^ 1,
+                5,
+                &tcp_quote(cookie.port, 443, cookie.seq),
+            ),
+            // Another source port or sequence number
+            unreachable(
+                cookie.ip_id,
+                5,
+                &tcp_quote(cookie.port ^ 1, 443, cookie.seq),
+            ),
+            unreachable(
+                cookie.ip_id,
+                5,
+                &tcp_quote(cookie.port, 443, cookie.seq ^ 1),
+            ),
+            // The cookie of another target port
+            unreachable(cookie.ip_id, 5, &tcp_quote(cookie.port, 80, cookie.seq)),
+        ];
+        for frame in frames {
+            assert!(validator.parse_frame(&frame).is_none());
+        }
+
+        // A probe sent by another host
+        let mut frame = unreachable(cookie.ip_id, 5, &tcp_quote(cookie.port, 443, cookie.seq));
+        frame[42 + 12..42 + 16].copy_from_slice(&Ipv4Addr::new(192, 0, 2, 2).octets());
+        assert!(validator.parse_frame(&frame).is_none());
+    }
+
+    #[test]
+    fn short_or_malformed_quotes_are_dropped() {
+        let validator = validator();
+        let cookie = cookie(&validator, 443);
+        let quote = tcp_quote(cookie.port, 443, cookie.seq);
+
+        // Fewer than the 8 bytes holding the ports and sequence number
+        assert!(validator
+            .parse_frame(&unreachable(cookie.ip_id, 5, &quote[..7]))
+            .is_none());
+
+        // Every truncation of a valid frame, down to the Ethernet header
+        let frame = unreachable(cookie.ip_id, 6, &quote);
+        for len in 0..frame.len() {
+            assert!(validator.parse_frame(&frame[..len]).is_none(), "{}", len);
+        }
+
+        // A quoted header length past the end of the quote
+        let mut frame = unreachable(cookie.ip_id, 5, &quote);
+        frame[42] = 0x4f;
+        assert!(validator.parse_frame(&frame).is_none());
+    }
+}
@@ -1,29 +1,159 @@
-use std::net::Ipv4Addr;
+use std::collections::VecDeque;
+use std::net::SocketAddrV4;
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, Instant};
 
 use crate::SharedRunState;
 
-use super::{cookie::CookieHasher, lcg, logger::LoggerStats, network_data::InterfaceData};
-use lcg::IPv4Iterator;
+use super::{
+    blocklist::Blocklist, cookie::CookieHasher, cyclic, logger::LoggerStats,
+    network_data::InterfaceData, probe::Probe, rate_limiter::RateLimiter,
+};
+use cyclic::IPv4Iterator;
 
-use pnet_packet::{ethernet::EtherTypes, ip::IpNextHeaderProtocols, tcp::TcpFlags};
+use pnet_packet::ethernet::EtherTypes;
 
 use pnet::datalink;
 use pnet::packet;
 
+/// Largest frame a probe can build
+const MAX_FRAME_LEN: usize = 1514;
+
+/// Builds the frames sent to each target, the layer 4 part is left to the probe
+pub(crate) struct PacketBuilder {
+    probe: Arc<dyn Probe>,
+    cookie_hasher: CookieHasher,
+    interface_data: InterfaceData,
+    ttl: u8,
+}
+
+impl PacketBuilder {
+    pub fn new(
+        probe: Arc<dyn Probe>,
+        cookie_hasher: CookieHasher,
+        interface_data: InterfaceData,
+        ttl: u8,
+    ) -> Self {
+        Self {
+            probe,
+            cookie_hasher,
+            interface_data,
+            ttl,
+        }
+    }
+
+    /// Writes the frame for `target` in `buffer`, returns its length
+    fn make_packet(&self, target: SocketAddrV4, buffer: &mut [u8]) -> usize {
+        let cookie = self
+            .cookie_hasher
+            .get_cookie(self.interface_data.device_ip, *target.ip(), target.port());
+        let l4_len = self.probe.make_packet(
+            self.interface_data.device_ip,
+            target,
+            cookie,
+            &mut buffer[34..],
+        );
+        {
+            let mut eth_header =
+                packet::ethernet::MutableEthernetPacket::new(&mut buffer[0..14]).unwrap();
+            eth_header.set_destination(self.interface_data.gateway_mac);
+            eth_header.set_source(self.interface_data.iface.mac.unwrap());
+            eth_header.set_ethertype(EtherTypes::Ipv4);
+        }
+        {
+            let mut ip_header = packet::ipv4::MutableIpv4Packet::new(&mut buffer[14..34]).unwrap();
+            ip_header.set_source(self.interface_data.device_ip);
+            ip_header.set_destination(*target.ip());
+            ip_header.set_header_length(5);
+            ip_header.set_total_length(20 + l4_len as u16);
+            ip_header.set_next_level_protocol(self.probe.protocol());
+            ip_header.set_identification(cookie.ip_id);
+            ip_header.set_ttl(self.ttl);
+            ip_header.set_version(4);
+
+            ip_header.set_checksum(0);
+            let checksum = pnet_packet::ipv4::checksum(&ip_header.to_immutable());
+            ip_header.set_checksum(checksum);
+        }
+        34 + l4_len
+    }
+}
+
+/// Copies a queue holds at most, a few dozen megabytes. Past it the sender
+/// waits for the oldest copy instead of moving on to new targets
+const MAX_PENDING_RETRIES: usize = 1 << 20;
+
+/// Copies of the probes still to be sent, every copy waiting the same delay
+/// the queue stays sorted by due time
+pub(crate) struct RetryQueue {
+    probes: u32,
+    delay: Duration,
+    queue: VecDeque<(Instant, SocketAddrV4, u32)>,
+}
+
+impl RetryQueue {
+    /// `probes` copies are sent to each target, `delay` apart
+    pub fn new(probes: u32, delay: Duration) -> Self {
+        Self {
+            probes,
+            delay,
+            queue: VecDeque::new(),
+        }
+    }
+
+    /// Records that `target` was just sent its `sent`th copy
+    fn sent(&mut self, target: SocketAddrV4, sent: u32) {
+        if sent < self.probes {
+            self.queue.push_back((Instant::now() + self.delay, target, sent));
+        }
+    }
+
+    fn is_full(&self) -> bool {
+        self.queue.len() >= MAX_PENDING_RETRIES
+    }
+
+    /// When the last queued copy is due
+    fn last_due(&self) -> Option<Instant> {
+        self.queue.back().map(|(due, _, _)| *due)
+    }
+
+    /// Next copy whose time came, along with the number already sent
+    fn pop_due(&mut self) -> Option<(SocketAddrV4, u32)> {
+        match self.queue.front() {
+            Some((due, _, _)) if *due <= Instant::now() => {
+                self.queue.pop_front().map(|(_, target, sent)| (target, sent))
+            }
+            _ => None,
+        }
+    }
+
+    /// Waits for the next copy, `None` once they are all sent
+    fn wait_next(&mut self) -> Option<(SocketAddrV4, u32)> {
+        let (due, target, sent) = self.queue.pop_front()?;
+        thread::sleep(due.saturating_duration_since(Instant::now()));
+        Some((target, sent))
+    }
+}
+
 pub(crate) struct PacketSender {
     ipv4_iterator: IPv4Iterator,
+    retries: RetryQueue,
+    blocklist: Blocklist,
+    rate_limiter: RateLimiter,
     channel: Box<dyn datalink::DataLinkSender>,
-    cookie_hasher: CookieHasher,
-    interface_data: super::network_data::InterfaceData,
+    packet_builder: PacketBuilder,
     stats: LoggerStats,
     run_state: SharedRunState,
 }
 
 impl PacketSender {
     pub fn new(
-        cookie_hasher: CookieHasher,
-        port: u16,
-        interface_data: InterfaceData,
+        packet_builder: PacketBuilder,
+        ipv4_iterator: IPv4Iterator,
+        retries: RetryQueue,
+        blocklist: Blocklist,
+        rate_limiter: RateLimiter,
         stats: LoggerStats,
         run_state: SharedRunState,
     ) -> PacketSender {
@@ -38,9 +168,8 @@ impl PacketSender {
             promiscuous: false,
         };
 
-        let pnet_channel = datalink::channel(&interface_data.iface, test).unwrap();
-
-        let ipv4_iterator: IPv4Iterator = IPv4Iterator::new(port);
+        let pnet_channel =
+            datalink::channel(&packet_builder.interface_data.iface, test).unwrap();
 
         let (tx, _) = match pnet_channel {
             pnet_datalink::Channel::Ethernet(sender, receiver) => (sender, receiver),
@@ -49,72 +178,99 @@ impl PacketSender {
 
         PacketSender {
             ipv4_iterator,
+            retries,
+            blocklist,
+            rate_limiter,
             channel: tx,
-            cookie_hasher,
-            interface_data,
+            packet_builder,
             stats,
             run_state,
         }
     }
 
-    fn make_packet(&self, dst_ip: Ipv4Addr, src_port: u16, dst_port: u16, buffer: &mut [u8]) {
-        {
-            let mut eth_header =
-                packet::ethernet::MutableEthernetPacket::new(&mut buffer[0..14]).unwrap();
-            eth_header.set_destination(self.interface_data.gateway_mac);
-            eth_header.set_source(self.interface_data.iface.mac.unwrap());
-            eth_header.set_ethertype(EtherTypes::Ipv4);
-        }
-        {
-            let mut ip_header = packet::ipv4::MutableIpv4Packet::new(&mut buffer[14..34]).unwrap();
-            ip_header.set_source(self.interface_data.device_ip.clone());
-            ip_header.set_destination(dst_ip.clone());
-            ip_header.set_header_length(5);
-            ip_header.set_total_length(40);
-            ip_header.set_next_level_protocol(IpNextHeaderProtocols::Tcp);
-            ip_header.set_identification(5);
-            ip_header.set_ttl(64);
-            ip_header.set_version(4);
+    pub fn send(&mut self) {
+        let mut packet_data = [0u8; MAX_FRAME_LEN];
+        loop {
+            self.run_state.act_state();
 
-            let checksum = pnet_packet::ipv4::checksum(&ip_header.to_immutable());
-            ip_header.set_checksum(checksum);
-        }
-        {
-            let mut tcp_header = packet::tcp::MutableTcpPacket::new(&mut buffer[34..54]).unwrap();
-            tcp_header.set_source(src_port);
-            tcp_header.set_destination(dst_port);
-            tcp_header.set_flags(TcpFlags::SYN);
-            tcp_header.set_window(64240);
-            tcp_header.set_data_offset(5);
-
-            tcp_header.set_checksum(0);
-            let checksum = pnet_packet::tcp::ipv4_checksum(
-                &tcp_header.to_immutable(),
-                &self.interface_data.device_ip,
-                &dst_ip,
-            );
-            tcp_header.set_checksum(checksum);
+            // Copies that are due go first, new targets fill the time in
+            // between unless the queue is full
+            let next = match self.retries.pop_due() {
+                Some(retry) => Some(retry),
+                None if self.retries.is_full() => self.retries.wait_next(),
+                None => match self.ipv4_iterator.next() {
+                    Some(curr_addr) => Some((curr_addr, 0)),
+                    None => self.retries.wait_next(),
+                },
+            };
+            let (curr_addr, sent) = match next {
+                Some(next) => next,
+                None => break,
+            };
+
+            if self.blocklist.contains(*curr_addr.ip()) {
+                let mut stats = self.stats.lock().unwrap();
+                stats.skipped += 1;
+                stats.position = self.ipv4_iterator.position();
+                stats.retries_end = self.retries.last_due();
+                continue;
+            }
+
+            self.retries.sent(curr_addr, sent + 1);
+            let len = self.packet_builder.make_packet(curr_addr, &mut packet_data);
+            self.rate_limiter.acquire(len);
+            self.channel.send_to(&packet_data[..len], None);
+
+            let mut stats = self.stats.lock().unwrap();
+            stats.sent += 1;
+            stats.position = self.ipv4_iterator.position();
+            stats.retries_end = self.retries.last_due();
         }
     }
+}
 
-    pub fn send(&mut self) {
-        let mut packet_data: [u8; 54] = [0u8; 54];
-        while let Some(curr_addr) = self.ipv4_iterator.next() {
-            self.run_state.act_state();
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::Ipv4Addr;
+
+    fn target(port: u16) -> SocketAddrV4 {
+        SocketAddrV4::new(Ipv4Addr::new(192, 0, 2, 1), port)
+    }
+
+    #[test]
+    fn retries_come_back_in_order_until_every_probe_is_sent() {
+        let mut retries = RetryQueue::new(3, Duration::ZERO);
+        retries.sent(target(1), 1);
+        retries.sent(target(2), 1);
+        retries.sent(target(3), 3);
+        assert_eq!(retries.pop_due(), Some((target(1), 1)));
+        retries.sent(target(1), 2);
+        assert_eq!(retries.pop_due(), Some((target(2), 1)));
+        assert_eq!(retries.wait_next(), Some((target(1), 2)));
+        retries.sent(target(1), 3);
+        assert_eq!(retries.pop_due(), None);
+        assert_eq!(retries.wait_next(), None);
+        assert_eq!(retries.last_due(), None);
+    }
+
+    #[test]
+    fn retries_wait_for_their_delay() {
+        let mut retries = RetryQueue::new(2, Duration::from_secs(60));
+        retries.sent(target(1), 1);
+        assert_eq!(retries.pop_due(), None);
+        assert!(retries.last_due().unwrap() > Instant::now() + Duration::from_secs(59));
+    }
 
-            let hash = self.cookie_hasher.get_port_cookie(
-                self.interface_data.device_ip.clone(),
-                curr_addr.ip().clone(),
-            );
-            self.make_packet(
-                curr_addr.ip().clone(),
-                hash,
-                curr_addr.port(),
-                &mut packet_data,
-            );
-            self.channel.send_to(&packet_data, None);
-
-            self.stats.lock().unwrap().sent += 1;
+    #[test]
+    fn queue_is_bounded() {
+        let mut retries = RetryQueue::new(2, Duration::ZERO);
+        for port in 0..MAX_PENDING_RETRIES {
+            assert!(!retries.is_full());
+            retries.sent(target(port as u16), 1);
         }
+        assert!(retries.is_full());
+        retries.pop_due();
+        assert!(!retries.is_full());
     }
 }
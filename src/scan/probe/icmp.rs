@@ -0,0 +1,78 @@
+use std::net::{Ipv4Addr, SocketAddrV4};
+
+use pnet_packet::icmp::echo_reply::EchoReplyPacket;
+use pnet_packet::icmp::echo_request::MutableEchoRequestPacket;
+use pnet_packet::icmp::{IcmpCode, IcmpPacket, IcmpTypes};
+use pnet_packet::ip::{IpNextHeaderProtocol, IpNextHeaderProtocols};
+use pnet_packet::ipv4::Ipv4Packet;
+use pnet_packet::Packet;
+
+use super::Probe;
+use crate::scan::cookie::{Cookie, CookieHasher};
+use crate::scan::output::{ScanResult, Status};
+
+/// Echo request carrying the cookie in its identifier and sequence number,
+/// targets have no port so the cookie is derived with port 0
+pub struct IcmpEchoProbe;
+
+impl Probe for IcmpEchoProbe {
+    fn protocol(&self) -> IpNextHeaderProtocol {
+        IpNextHeaderProtocols::Icmp
+    }
+
+    fn make_packet(
+        &self,
+        _src_ip: Ipv4Addr,
+        _target: SocketAddrV4,
+        cookie: Cookie,
+        buffer: &mut [u8],
+    ) -> usize {
+        let mut echo_request = MutableEchoRequestPacket::new(&mut buffer[..8]).unwrap();
+        echo_request.set_icmp_type(IcmpTypes::EchoRequest);
+        echo_request.set_icmp_code(IcmpCode(0));
+        echo_request.set_identifier(cookie.port);
+        echo_request.set_sequence_number(cookie.seq as u16);
+
+        echo_request.set_checksum(0);
+        let checksum = pnet_packet::icmp::checksum(&IcmpPacket::new(echo_request.packet()).unwrap());
+        echo_request.set_checksum(checksum);
+
+        8
+    }
+
+    fn classify(&self, ip: &Ipv4Packet, cookie_hasher: &CookieHasher) -> Option<ScanResult> {
+        let echo_reply = EchoReplyPacket::new(ip.payload())?;
+        if echo_reply.get_icmp_type() != IcmpTypes::EchoReply {
+            return None;
+        }
+
+        let target = SocketAddrV4::new(ip.get_source(), 0);
+        let cookie = cookie_hasher.get_cookie(ip.get_destination(), *target.ip(), target.port());
+        if echo_reply.get_identifier() != cookie.port
+            || echo_reply.get_sequence_number() != cookie.seq as u16
+        {
+            return None;
+        }
+
+        Some(ScanResult::new(target, Status::Up))
+    }
+
+    fn classify_unreachable(
+        &self,
+        quoted_ip: &Ipv4Packet,
+        quoted: &[u8],
+        _code: u8,
+        cookie_hasher: &CookieHasher,
+    ) -> Option<ScanResult> {
+        let identifier = u16::from_be_bytes([quoted[4], quoted[5]]);
+        let sequence_number = u16::from_be_bytes([quoted[6], quoted[7]]);
+
+        let target = SocketAddrV4::new(quoted_ip.get_destination(), 0);
+        let cookie = cookie_hasher.get_cookie(quoted_ip.get_source(), *target.ip(), target.port());
+        if identifier != cookie.port || sequence_number != cookie.seq as u16 {
+            return None;
+        }
+
+        Some(ScanResult::new(target, Status::Filtered))
+    }
+}
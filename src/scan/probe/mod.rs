@@ -0,0 +1,114 @@
+use std::fmt;
+use std::net::{Ipv4Addr, SocketAddrV4};
+use std::sync::Arc;
+
+use clap::ValueEnum;
+use pnet_packet::ip::IpNextHeaderProtocol;
+use pnet_packet::ipv4::Ipv4Packet;
+
+use super::cookie::{Cookie, CookieHasher};
+use super::output::ScanResult;
+use tcp::{TcpOptions, TcpProbe, TcpScan};
+
+pub mod icmp;
+pub mod tcp;
+pub mod udp;
+
+/// Kind of packet sent to the targets, along with how its replies are read
+pub trait Probe: Send + Sync {
+    /// IP protocol the probe is sent over
+    fn protocol(&self) -> IpNextHeaderProtocol;
+
+    /// Writes the layer 4 packet for `target` at the start of `buffer`, returns its length
+    fn make_packet(
+        &self,
+        src_ip: Ipv4Addr,
+        target: SocketAddrV4,
+        cookie: Cookie,
+        buffer: &mut [u8],
+    ) -> usize;
+
+    /// Reads a reply of the probe protocol, `None` if it doesn't answer one of our probes
+    fn classify(&self, ip: &Ipv4Packet, cookie_hasher: &CookieHasher) -> Option<ScanResult>;
+
+    /// Reads the start of one of our probes quoted by an ICMP destination unreachable
+    /// with `code`, `quoted` holds at least the first 8 bytes of the layer 4 header.
+    /// `None` if the quote doesn't match the cookie
+    fn classify_unreachable(
+        &self,
+        quoted_ip: &Ipv4Packet,
+        quoted: &[u8],
+        code: u8,
+        cookie_hasher: &CookieHasher,
+    ) -> Option<ScanResult>;
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, ValueEnum)]
+pub enum ProbeType {
+    /// TCP SYN, open ports answer with a SYN-ACK
+    TcpSyn,
+    /// TCP ACK, unfiltered ports answer with a RST
+    TcpAck,
+    /// TCP FIN, closed ports answer with a RST
+    TcpFin,
+    /// TCP without flags, closed ports answer with a RST
+    TcpNull,
+    /// TCP FIN, PSH and URG, closed ports answer with a RST
+    TcpXmas,
+    /// TCP ACK reading the window of the RST replies
+    TcpWindow,
+    /// UDP datagram with a payload chosen from the port
+    Udp,
+    /// ICMP echo request, live hosts answer with an echo reply
+    IcmpEcho,
+}
+
+impl ProbeType {
+    /// `tcp_options` are the options of the SYNs sent by the tcp-syn probe
+    pub fn build(&self, tcp_options: TcpOptions) -> Arc<dyn Probe> {
+        match self {
+            ProbeType::TcpSyn => Arc::new(TcpProbe::new(TcpScan::Syn, tcp_options)),
+            ProbeType::TcpAck => Arc::new(TcpProbe::new(TcpScan::Ack, tcp_options)),
+            ProbeType::TcpFin => Arc::new(TcpProbe::new(TcpScan::Fin, tcp_options)),
+            ProbeType::TcpNull => Arc::new(TcpProbe::new(TcpScan::Null, tcp_options)),
+            ProbeType::TcpXmas => Arc::new(TcpProbe::new(TcpScan::Xmas, tcp_options)),
+            ProbeType::TcpWindow => Arc::new(TcpProbe::new(TcpScan::Window, tcp_options)),
+            ProbeType::Udp => Arc::new(udp::UdpProbe),
+            ProbeType::IcmpEcho => Arc::new(icmp::IcmpEchoProbe),
+        }
+    }
+
+    /// Whether the targets are (address, port) pairs rather than hosts
+    pub fn uses_ports(&self) -> bool {
+        !matches!(self, ProbeType::IcmpEcho)
+    }
+
+    /// Whether closed ports are the only answer the probe gets, so they are
+    /// reported without --report-closed
+    pub fn only_finds_closed(&self) -> bool {
+        matches!(
+            self,
+            ProbeType::TcpFin | ProbeType::TcpNull | ProbeType::TcpXmas
+        )
+    }
+}
+
+/// Same name as on the command line
+impl fmt::Display for ProbeType {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.to_possible_value().unwrap().get_name())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn names_round_trip_through_the_command_line_form() {
+        for probe in ProbeType::value_variants() {
+            assert_eq!(ProbeType::from_str(&probe.to_string(), false), Ok(*probe));
+        }
+        assert_eq!(ProbeType::IcmpEcho.to_string(), "icmp-echo");
+    }
+}
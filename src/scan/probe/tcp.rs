@@ -0,0 +1,460 @@
+use std::net::{Ipv4Addr, SocketAddrV4};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use clap::ValueEnum;
+
+use pnet_packet::ip::{IpNextHeaderProtocol, IpNextHeaderProtocols};
+use pnet_packet::ipv4::Ipv4Packet;
+use pnet_packet::tcp::{MutableTcpPacket, TcpFlags, TcpPacket};
+use pnet_packet::Packet;
+
+use super::Probe;
+use crate::scan::cookie::{Cookie, CookieHasher};
+use crate::scan::output::{ScanResult, Status};
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum TcpScan {
+    /// SYN-ACK for open ports, RST for closed ones
+    Syn,
+    /// RST from unfiltered ports whatever their state, maps firewall rules
+    Ack,
+    /// RST from closed ports, open ones drop the probe
+    Fin,
+    Null,
+    Xmas,
+    /// ACK scan reading the window of the RST, non zero on some stacks for open ports
+    Window,
+}
+
+impl TcpScan {
+    fn flags(&self) -> u8 {
+        match self {
+            TcpScan::Syn => TcpFlags::SYN,
+            TcpScan::Ack | TcpScan::Window => TcpFlags::ACK,
+            TcpScan::Fin => TcpFlags::FIN,
+            TcpScan::Null => 0,
+            TcpScan::Xmas => TcpFlags::FIN | TcpFlags::PSH | TcpFlags::URG,
+        }
+    }
+
+    /// Probes with the ACK flag are answered with a RST whose sequence number
+    /// is our acknowledgment, so the cookie goes there instead
+    fn cookie_in_ack(&self) -> bool {
+        self.flags() & TcpFlags::ACK == TcpFlags::ACK
+    }
+}
+
+/// Longest options block of the presets
+const MAX_OPTIONS_LEN: usize = 24;
+
+const MSS_1460: [u8; 4] = [2, 4, 0x05, 0xb4];
+const SACK_PERM: [u8; 2] = [4, 2];
+const NOP: u8 = 1;
+const EOL: u8 = 0;
+
+/// Options of the SYN probes, copied from common client stacks so our SYNs
+/// don't stand out from ordinary connection attempts
+#[derive(Clone, Copy, Debug, PartialEq, Eq, ValueEnum)]
+pub enum TcpOptions {
+    Linux,
+    Windows,
+    Macos,
+    None,
+}
+
+impl TcpOptions {
+    /// Writes the options at the start of `buffer`, returns their length, a multiple of 4
+    fn write(&self, timestamp: u32, buffer: &mut [u8]) -> usize {
+        let mut ts = [8, 10, 0, 0, 0, 0, 0, 0, 0, 0];
+        ts[2..6].copy_from_slice(&timestamp.to_be_bytes());
+
+        let mut len = 0;
+        let mut push = |bytes: &[u8]| {
+            buffer[len..len + bytes.len()].copy_from_slice(bytes);
+            len += bytes.len();
+        };
+        match self {
+            TcpOptions::Linux => {
+                push(&MSS_1460);
+                push(&SACK_PERM);
+                push(&ts);
+                push(&[NOP, 3, 3, 7]);
+            }
+            TcpOptions::Windows => {
+                push(&MSS_1460);
+                push(&[NOP, 3, 3, 8]);
+                push(&[NOP, NOP]);
+                push(&SACK_PERM);
+            }
+            TcpOptions::Macos => {
+                push(&MSS_1460);
+                push(&[NOP, 3, 3, 6]);
+                push(&[NOP, NOP]);
+                push(&ts);
+                push(&SACK_PERM);
+                push(&[EOL, EOL]);
+            }
+            TcpOptions::None => {}
+        }
+        len
+    }
+
+    fn window(&self) -> u16 {
+        match self {
+            TcpOptions::Macos => 65535,
+            _ => 64240,
+        }
+    }
+}
+
+pub struct TcpProbe {
+    scan: TcpScan,
+    options: TcpOptions,
+}
+
+impl TcpProbe {
+    /// Only SYN probes carry `options`, the other flavours stand for segments
+    /// of a connection already set up
+    pub fn new(scan: TcpScan, options: TcpOptions) -> Self {
+        let options = match scan {
+            TcpScan::Syn => options,
+            _ => TcpOptions::None,
+        };
+        Self { scan, options }
+    }
+
+    /// Checks the reply echoes the cookie back, in the sequence number for
+    /// probes carrying it in their acknowledgment, as an acknowledgment of the
+    /// probe otherwise
+    fn check_reply(
+        &self,
+        ip: &Ipv4Packet,
+        packet: &TcpPacket,
+        cookie_hasher: &CookieHasher,
+    ) -> bool {
+        let cookie =
+            cookie_hasher.get_cookie(ip.get_destination(), ip.get_source(), packet.get_source());
+        if cookie.port != packet.get_destination() {
+            return false;
+        }
+
+        if self.scan.cookie_in_ack() {
+            return packet.get_sequence() == cookie.seq;
+        }
+        // SYN and FIN both take a sequence number
+        let consumed = match self.scan.flags() & (TcpFlags::SYN | TcpFlags::FIN) {
+            0 => 0,
+            _ => 1,
+        };
+        packet.get_acknowledgement() == cookie.seq.wrapping_add(consumed)
+    }
+}
+
+impl Probe for TcpProbe {
+    fn protocol(&self) -> IpNextHeaderProtocol {
+        IpNextHeaderProtocols::Tcp
+    }
+
+    fn make_packet(
+        &self,
+        src_ip: Ipv4Addr,
+        target: SocketAddrV4,
+        cookie: Cookie,
+        buffer: &mut [u8],
+    ) -> usize {
+        // Timestamps tick in milliseconds like the Linux clock
+        let timestamp = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_millis() as u32;
+        let mut options = [0u8; MAX_OPTIONS_LEN];
+        let options_len = self.options.write(timestamp, &mut options);
+        let len = 20 + options_len;
+        buffer[20..len].copy_from_slice(&options[..options_len]);
+
+        let mut tcp_header = MutableTcpPacket::new(&mut buffer[..len]).unwrap();
+        tcp_header.set_source(cookie.port);
+        tcp_header.set_destination(target.port());
+        if self.scan.cookie_in_ack() {
+            tcp_header.set_sequence(0);
+            tcp_header.set_acknowledgement(cookie.seq);
+        } else {
+            tcp_header.set_sequence(cookie.seq);
+            tcp_header.set_acknowledgement(0);
+        }
+        tcp_header.set_flags(self.scan.flags());
+        tcp_header.set_window(self.options.window());
+        tcp_header.set_data_offset((len / 4) as u8);
+
+        tcp_header.set_checksum(0);
+        let checksum =
+            pnet_packet::tcp::ipv4_checksum(&tcp_header.to_immutable(), &src_ip, target.ip());
+        tcp_header.set_checksum(checksum);
+
+        len
+    }
+
+    fn classify(&self, ip: &Ipv4Packet, cookie_hasher: &CookieHasher) -> Option<ScanResult> {
+        let packet = TcpPacket::new(ip.payload())?;
+
+        let flags = packet.get_flags();
+        let syn_ack = flags & (TcpFlags::SYN | TcpFlags::ACK) == TcpFlags::SYN | TcpFlags::ACK;
+        let rst = flags & TcpFlags::RST == TcpFlags::RST;
+
+        let status = match self.scan {
+            TcpScan::Syn if syn_ack => Status::Open,
+            TcpScan::Syn if rst => Status::Closed,
+            TcpScan::Ack if rst => Status::Unfiltered,
+            TcpScan::Fin | TcpScan::Null | TcpScan::Xmas if rst => Status::Closed,
+            TcpScan::Window if rst && packet.get_window() > 0 => Status::Open,
+            TcpScan::Window if rst => Status::Closed,
+            _ => return None,
+        };
+
+        if !self.check_reply(ip, &packet, cookie_hasher) {
+            return None;
+        }
+
+        let mut result = ScanResult::new(
+            SocketAddrV4::new(ip.get_source(), packet.get_source()),
+            status,
+        );
+        result.window = Some(packet.get_window());
+        result.tcp_options = Some(packet.get_options_raw().to_vec());
+        Some(result)
+    }
+
+    fn classify_unreachable(
+        &self,
+        quoted_ip: &Ipv4Packet,
+        quoted: &[u8],
+        _code: u8,
+        cookie_hasher: &CookieHasher,
+    ) -> Option<ScanResult> {
+        let src_port = u16::from_be_bytes([quoted[0], quoted[1]]);
+        let dst_port = u16::from_be_bytes([quoted[2], quoted[3]]);
+        let seq = u32::from_be_bytes([quoted[4], quoted[5], quoted[6], quoted[7]]);
+
+        let target_ip = quoted_ip.get_destination();
+        let cookie = cookie_hasher.get_cookie(quoted_ip.get_source(), target_ip, dst_port);
+        // The acknowledgment isn't part of the quoted 8 bytes
+        let seq_matches = self.scan.cookie_in_ack() || cookie.seq == seq;
+        if cookie.port != src_port || !seq_matches {
+            return None;
+        }
+
+        Some(ScanResult::new(
+            SocketAddrV4::new(target_ip, dst_port),
+            Status::Filtered,
+        ))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn option_presets_are_padded_and_fit_the_buffer() {
+        let expected = [
+            (TcpOptions::Linux, 20),
+            (TcpOptions::Windows, 12),
+            (TcpOptions::Macos, 24),
+            (TcpOptions::None, 0),
+        ];
+        for (options, len) in expected {
+            let mut buffer = [0xff; MAX_OPTIONS_LEN];
+            assert_eq!(
+                options.write(0x0102_0304, &mut buffer),
+                len,
+                "{:?}",
+                options
+            );
+            assert_eq!(len % 4, 0);
+            if len > 0 {
+                assert_eq!(buffer[..4], MSS_1460, "{:?}", options);
+            }
+        }
+
+        let mut buffer = [0; MAX_OPTIONS_LEN];
+        TcpOptions::Linux.write(0x0102_0304, &mut buffer);
+        assert_eq!(buffer[6..16], [8, 10, 1, 2, 3, 4, 0, 0, 0, 0]);
+    }
+
+    #[test]
+    fn only_syn_probes_carry_options() {
+        for scan in [
+            TcpScan::Ack,
+            TcpScan::Fin,
+            TcpScan::Null,
+            TcpScan::Xmas,
+            TcpScan::Window,
+        ] {
+            assert_eq!(
+                TcpProbe::new(scan, TcpOptions::Linux).options,
+                TcpOptions::None
+            );
+        }
+        assert_eq!(
+            TcpProbe::new(TcpScan::Syn, TcpOptions::Macos).options,
+            TcpOptions::Macos
+        );
+    }
+
+    const DEVICE: Ipv4Addr = Ipv4Addr::new(192, 0, 2, 1);
+    const TARGET: Ipv4Addr = Ipv4Addr::new(198, 51, 100, 7);
+
+    fn hasher() -> CookieHasher {
+        use rand::SeedableRng;
+        CookieHasher::new(
+            &mut rand_chacha::ChaCha8Rng::seed_from_u64(1),
+            32768..=60999,
+        )
+    }
+
+    /// IPv4 packet from the target holding a TCP segment to `port`
+    fn reply(port: u16, flags: u8, seq: u32, ack: u32, window: u16) -> Vec<u8> {
+        let mut packet = vec![0u8; 40];
+        let mut ip = pnet_packet::ipv4::MutableIpv4Packet::new(&mut packet).unwrap();
+        ip.set_version(4);
+        ip.set_header_length(5);
+        ip.set_total_length(40);
+        ip.set_next_level_protocol(IpNextHeaderProtocols::Tcp);
+        ip.set_source(TARGET);
+        ip.set_destination(DEVICE);
+
+        let mut tcp = MutableTcpPacket::new(&mut packet[20..]).unwrap();
+        tcp.set_source(443);
+        tcp.set_destination(port);
+        tcp.set_sequence(seq);
+        tcp.set_acknowledgement(ack);
+        tcp.set_flags(flags);
+        tcp.set_window(window);
+        tcp.set_data_offset(5);
+        packet
+    }
+
+    /// Reply echoing the cookie the way the target's stack does for `scan`
+    fn valid_reply(scan: TcpScan, flags: u8, window: u16) -> Vec<u8> {
+        let cookie = hasher().get_cookie(DEVICE, TARGET, 443);
+        match scan {
+            TcpScan::Syn | TcpScan::Fin | TcpScan::Xmas => {
+                reply(cookie.port, flags, 0, cookie.seq.wrapping_add(1), window)
+            }
+            TcpScan::Null => reply(cookie.port, flags, 0, cookie.seq, window),
+            TcpScan::Ack | TcpScan::Window => reply(cookie.port, flags, cookie.seq, 0, window),
+        }
+    }
+
+    fn classify(scan: TcpScan, packet: &[u8]) -> Option<ScanResult> {
+        let probe = TcpProbe::new(scan, TcpOptions::None);
+        probe.classify(&Ipv4Packet::new(packet).unwrap(), &hasher())
+    }
+
+    #[test]
+    fn reply_flags_give_the_status() {
+        let (syn_ack, rst, ack) = (
+            TcpFlags::SYN | TcpFlags::ACK,
+            TcpFlags::RST | TcpFlags::ACK,
+            TcpFlags::ACK,
+        );
+        let expected = [
+            (TcpScan::Syn, syn_ack, 64240, Some(Status::Open)),
+            (TcpScan::Syn, rst, 0, Some(Status::Closed)),
+            (TcpScan::Syn, ack, 64240, None),
+            (TcpScan::Ack, TcpFlags::RST, 0, Some(Status::Unfiltered)),
+            (TcpScan::Ack, syn_ack, 0, None),
+            (TcpScan::Fin, rst, 0, Some(Status::Closed)),
+            (TcpScan::Null, rst, 0, Some(Status::Closed)),
+            (TcpScan::Xmas, rst, 0, Some(Status::Closed)),
+            (TcpScan::Xmas, syn_ack, 0, None),
+            (TcpScan::Window, TcpFlags::RST, 0, Some(Status::Closed)),
+            (TcpScan::Window, TcpFlags::RST, 1024, Some(Status::Open)),
+        ];
+        for (scan, flags, window, status) in expected {
+            let result = classify(scan, &valid_reply(scan, flags, window));
+            assert_eq!(
+                result.map(|result| result.status),
+                status,
+                "{:?} {:#x}",
+                scan,
+                flags
+            );
+        }
+
+        let result = classify(TcpScan::Syn, &valid_reply(TcpScan::Syn, syn_ack, 64240)).unwrap();
+        assert_eq!(result.addr, SocketAddrV4::new(TARGET, 443));
+        assert_eq!(result.window, Some(64240));
+        assert_eq!(result.tcp_options, Some(vec![]));
+    }
+
+    #[test]
+    fn replies_must_echo_the_cookie() {
+        let cookie = hasher().get_cookie(DEVICE, TARGET, 443);
+        let rst = TcpFlags::RST | TcpFlags::ACK;
+        let dropped = [
+            // A SYN-ACK acknowledges the SYN, one past the cookie
+            (
+                TcpScan::Syn,
+                reply(cookie.port, TcpFlags::SYN | TcpFlags::ACK, 0, cookie.seq, 0),
+            ),
+            // FIN takes a sequence number like SYN, NULL doesn't
+            (TcpScan::Fin, reply(cookie.port, rst, 0, cookie.seq, 0)),
+            (
+                TcpScan::Null,
+                reply(cookie.port, rst, 0, cookie.seq.wrapping_add(1), 0),
+            ),
+            // The RST to an ACK takes its sequence number from our acknowledgment
+            (
+                TcpScan::Ack,
+                reply(cookie.port, TcpFlags::RST, 0, cookie.seq, 0),
+            ),
+            (
+                TcpScan::Window,
+                reply(cookie.port, TcpFlags::RST, cookie.seq ^ 1, 0, 0),
+            ),
+            // Sent to another port than the cookie's
+            (TcpScan::Null, reply(cookie.port ^ 1, rst, 0, cookie.seq, 0)),
+        ];
+        for (scan, packet) in dropped {
+            assert!(classify(scan, &packet).is_none(), "{:?}", scan);
+        }
+    }
+
+    #[test]
+    fn probes_carry_the_cookie_where_the_reply_echoes_it() {
+        let cookie = hasher().get_cookie(DEVICE, TARGET, 443);
+        let target = SocketAddrV4::new(TARGET, 443);
+        let mut buffer = [0u8; 64];
+
+        for scan in [TcpScan::Syn, TcpScan::Fin, TcpScan::Null, TcpScan::Xmas] {
+            let len = TcpProbe::new(scan, TcpOptions::None).make_packet(
+                DEVICE,
+                target,
+                cookie,
+                &mut buffer,
+            );
+            let probe = TcpPacket::new(&buffer[..len]).unwrap();
+            assert_eq!(
+                (probe.get_sequence(), probe.get_acknowledgement()),
+                (cookie.seq, 0)
+            );
+            assert_eq!(probe.get_source(), cookie.port);
+            assert_eq!(probe.get_flags(), scan.flags());
+        }
+        for scan in [TcpScan::Ack, TcpScan::Window] {
+            let len = TcpProbe::new(scan, TcpOptions::None).make_packet(
+                DEVICE,
+                target,
+                cookie,
+                &mut buffer,
+            );
+            let probe = TcpPacket::new(&buffer[..len]).unwrap();
+            assert_eq!(
+                (probe.get_sequence(), probe.get_acknowledgement()),
+                (0, cookie.seq)
+            );
+            assert_eq!(probe.get_flags(), TcpFlags::ACK);
+        }
+    }
+}
@@ -0,0 +1,329 @@
+use std::net::{Ipv4Addr, SocketAddrV4};
+
+use pnet_packet::ip::{IpNextHeaderProtocol, IpNextHeaderProtocols};
+use pnet_packet::ipv4::Ipv4Packet;
+use pnet_packet::udp::{MutableUdpPacket, UdpPacket};
+use pnet_packet::Packet;
+
+use super::Probe;
+use crate::scan::cookie::{Cookie, CookieHasher};
+use crate::scan::output::{ScanResult, Status};
+
+/// ICMP destination unreachable code for a closed UDP port
+const PORT_UNREACHABLE: u8 = 3;
+
+/// Sends a payload the service on the port answers to, and carries part of
+/// the cookie in it when the protocol echoes an identifier back
+pub struct UdpProbe;
+
+impl Probe for UdpProbe {
+    fn protocol(&self) -> IpNextHeaderProtocol {
+        IpNextHeaderProtocols::Udp
+    }
+
+    fn make_packet(
+        &self,
+        src_ip: Ipv4Addr,
+        target: SocketAddrV4,
+        cookie: Cookie,
+        buffer: &mut [u8],
+    ) -> usize {
+        let payload_len = write_payload(target.port(), cookie, &mut buffer[8..]);
+        let len = 8 + payload_len;
+
+        let mut udp_header = MutableUdpPacket::new(&mut buffer[..len]).unwrap();
+        udp_header.set_source(cookie.port);
+        udp_header.set_destination(target.port());
+        udp_header.set_length(len as u16);
+
+        udp_header.set_checksum(0);
+        let checksum =
+            pnet_packet::udp::ipv4_checksum(&udp_header.to_immutable(), &src_ip, target.ip());
+        udp_header.set_checksum(checksum);
+
+        len
+    }
+
+    fn classify(&self, ip: &Ipv4Packet, cookie_hasher: &CookieHasher) -> Option<ScanResult> {
+        let packet = UdpPacket::new(ip.payload())?;
+
+        let target = SocketAddrV4::new(ip.get_source(), packet.get_source());
+        let cookie = cookie_hasher.get_cookie(ip.get_destination(), *target.ip(), target.port());
+        if cookie.port != packet.get_destination()
+            || !check_payload(target.port(), cookie, packet.payload())
+        {
+            return None;
+        }
+
+        Some(ScanResult::new(target, Status::Open))
+    }
+
+    fn classify_unreachable(
+        &self,
+        quoted_ip: &Ipv4Packet,
+        quoted: &[u8],
+        code: u8,
+        cookie_hasher: &CookieHasher,
+    ) -> Option<ScanResult> {
+        let src_port = u16::from_be_bytes([quoted[0], quoted[1]]);
+        let dst_port = u16::from_be_bytes([quoted[2], quoted[3]]);
+
+        let target_ip = quoted_ip.get_destination();
+        let cookie = cookie_hasher.get_cookie(quoted_ip.get_source(), target_ip, dst_port);
+        if cookie.port != src_port {
+            return None;
+        }
+
+        let status = match code {
+            PORT_UNREACHABLE => Status::Closed,
+            _ => Status::Filtered,
+        };
+        Some(ScanResult::new(SocketAddrV4::new(target_ip, dst_port), status))
+    }
+}
+
+const DNS_QUERY: [u8; 17] = [
+    0x00, 0x00, // Transaction id, from the cookie
+    0x01, 0x00, // Standard query, recursion desired
+    0x00, 0x01, // One question
+    0x00, 0x00, 0x00, 0x00, 0x00, 0x00, // No answer, authority or additional records
+    0x00, // Root name
+    0x00, 0x02, // NS
+    0x00, 0x01, // IN
+];
+
+const NETBIOS_NBSTAT: [u8; 50] = [
+    0x00, 0x00, // Transaction id, from the cookie
+    0x00, 0x00, // Query
+    0x00, 0x01, // One question
+    0x00, 0x00, 0x00, 0x00, 0x00, 0x00, // No answer, authority or additional records
+    0x20, b'C', b'K', b'A', b'A', b'A', b'A', b'A', b'A', b'A', b'A', b'A', b'A', b'A', b'A',
+    b'A', b'A', b'A', b'A', b'A', b'A', b'A', b'A', b'A', b'A', b'A', b'A', b'A', b'A', b'A',
+    b'A', b'A', b'A', 0x00, // Wildcard name "*"
+    0x00, 0x21, // NBSTAT
+    0x00, 0x01, // IN
+];
+
+/// NTP v4 client request, the transmit timestamp carries the cookie and is
+/// echoed back as the origin timestamp
+const NTP_REQUEST_LEN: usize = 48;
+
+/// SNMPv1 GetRequest of sysDescr.0 with the "public" community, the request
+/// id carries the cookie and is echoed back in the GetResponse
+const SNMP_GET: [u8; 43] = [
+    0x30, 0x29, // Message
+    0x02, 0x01, 0x00, // Version 1
+    0x04, 0x06, b'p', b'u', b'b', b'l', b'i', b'c', // Community
+    0xa0, 0x1c, // GetRequest
+    0x02, 0x04, 0x00, 0x00, 0x00, 0x00, // Request id
+    0x02, 0x01, 0x00, // Error status
+    0x02, 0x01, 0x00, // Error index
+    0x30, 0x0e, 0x30, 0x0c, // Variable bindings
+    0x06, 0x08, 0x2b, 0x06, 0x01, 0x02, 0x01, 0x01, 0x01, 0x00, // 1.3.6.1.2.1.1.1.0
+    0x05, 0x00, // Null
+];
+
+const SSDP_SEARCH: &[u8] = b"M-SEARCH * HTTP/1.1\r\nHOST: 239.255.255.250:1900\r\nMAN: \"ssdp:discover\"\r\nMX: 1\r\nST: ssdp:all\r\n\r\n";
+
+/// Memcached UDP frame header followed by a stats command, the request id
+/// carries the cookie
+const MEMCACHED_STATS: [u8; 15] = [
+    0x00, 0x00, // Request id
+    0x00, 0x00, // Sequence number
+    0x00, 0x01, // Total datagrams
+    0x00, 0x00, // Reserved
+    b's', b't', b'a', b't', b's', b'\r', b'\n',
+];
+
+/// Writes the payload for a service on `port`, nothing for unknown ports
+fn write_payload(port: u16, cookie: Cookie, buffer: &mut [u8]) -> usize {
+    let id = (cookie.seq as u16).to_be_bytes();
+    let payload: &[u8] = match port {
+        53 => &DNS_QUERY,
+        123 => &[0u8; NTP_REQUEST_LEN],
+        137 => &NETBIOS_NBSTAT,
+        161 => &SNMP_GET,
+        1900 => SSDP_SEARCH,
+        11211 => &MEMCACHED_STATS,
+        _ => &[],
+    };
+    buffer[..payload.len()].copy_from_slice(payload);
+
+    match port {
+        53 | 137 | 11211 => buffer[..2].copy_from_slice(&id),
+        123 => {
+            buffer[0] = 0xe3; // No leap warning, version 4, client mode
+            buffer[40..44].copy_from_slice(&cookie.seq.to_be_bytes());
+        }
+        161 => buffer[17..21].copy_from_slice(&cookie.seq.to_be_bytes()),
+        _ => {}
+    }
+
+    payload.len()
+}
+
+/// Checks the identifier the service echoed back, when the protocol has one
+fn check_payload(port: u16, cookie: Cookie, payload: &[u8]) -> bool {
+    let id = (cookie.seq as u16).to_be_bytes();
+    match port {
+        53 | 137 | 11211 => payload.get(..2) == Some(&id[..]),
+        123 => payload.get(24..28) == Some(&cookie.seq.to_be_bytes()[..]),
+        // Agents decode the request id and encode it back in as few bytes as it takes
+        161 => snmp_request_id(payload) == Some(cookie.seq as i32 as i64),
+        _ => true,
+    }
+}
+
+/// Splits a BER element with `tag` at the start of `data` into its value and what follows
+fn ber_element(data: &[u8], tag: u8) -> Option<(&[u8], &[u8])> {
+    let (&first, rest) = data.split_first()?;
+    if first != tag {
+        return None;
+    }
+    let (&len, mut rest) = rest.split_first()?;
+    let len = if len & 0x80 == 0 {
+        len as usize
+    } else {
+        let (len_bytes, after) = rest.split_at_checked((len & 0x7f) as usize)?;
+        rest = after;
+        if len_bytes.len() > 4 {
+            return None;
+        }
+        len_bytes
+            .iter()
+            .fold(0, |len, byte| len << 8 | *byte as usize)
+    };
+    rest.split_at_checked(len)
+}
+
+/// Request id of an SNMP GetResponse
+fn snmp_request_id(payload: &[u8]) -> Option<i64> {
+    let (message, _) = ber_element(payload, 0x30)?;
+    let (_version, rest) = ber_element(message, 0x02)?;
+    let (_community, rest) = ber_element(rest, 0x04)?;
+    let (pdu, _) = ber_element(rest, 0xa2)?;
+    let (id, _) = ber_element(pdu, 0x02)?;
+    if id.is_empty() || id.len() > 5 {
+        return None;
+    }
+    // Two's complement, the sign bit fills the bits above the value
+    let sign = if id[0] & 0x80 != 0 { -1 } else { 0 };
+    Some(
+        id.iter()
+            .fold(sign, |value, byte| value << 8 | *byte as i64),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const COOKIE: Cookie = Cookie {
+        port: 40000,
+        seq: 0x8765_4321,
+        ip_id: 7,
+    };
+
+    fn payload(port: u16, cookie: Cookie) -> Vec<u8> {
+        let mut buffer = [0u8; 128];
+        let len = write_payload(port, cookie, &mut buffer);
+        buffer[..len].to_vec()
+    }
+
+    /// GetResponse echoing `request_id` as encoded by the agent
+    fn snmp_response(request_id: &[u8]) -> Vec<u8> {
+        let varbind = [
+            0x30, 0x0f, 0x30, 0x0d, 0x06, 0x08, 0x2b, 0x06, 0x01, 0x02, 0x01, 0x01, 0x01, 0x00,
+            0x04, 0x01, b'x',
+        ];
+        let pdu_len = 2 + request_id.len() + 6 + varbind.len();
+        let mut pdu = vec![0xa2, pdu_len as u8, 0x02, request_id.len() as u8];
+        pdu.extend(request_id);
+        pdu.extend([0x02, 0x01, 0x00, 0x02, 0x01, 0x00]);
+        pdu.extend(varbind);
+
+        let mut message = vec![0x02, 0x01, 0x00, 0x04, 0x06];
+        message.extend(b"public");
+        message.extend(pdu);
+        [&[0x30, message.len() as u8][..], &message].concat()
+    }
+
+    #[test]
+    fn payloads_carry_the_cookie() {
+        let id = (COOKIE.seq as u16).to_be_bytes();
+        for port in [53, 137, 11211] {
+            assert_eq!(payload(port, COOKIE)[..2], id, "{}", port);
+        }
+        assert_eq!(payload(53, COOKIE).len(), DNS_QUERY.len());
+
+        let ntp = payload(123, COOKIE);
+        assert_eq!(ntp.len(), NTP_REQUEST_LEN);
+        assert_eq!(ntp[0], 0xe3);
+        assert_eq!(ntp[40..44], COOKIE.seq.to_be_bytes());
+
+        let snmp = payload(161, COOKIE);
+        assert_eq!(snmp[15..17], [0x02, 0x04]);
+        assert_eq!(snmp[17..21], COOKIE.seq.to_be_bytes());
+        assert_eq!(snmp[1] as usize, snmp.len() - 2);
+
+        assert_eq!(payload(1900, COOKIE), SSDP_SEARCH);
+        assert!(payload(8080, COOKIE).is_empty());
+    }
+
+    #[test]
+    fn replies_must_echo_the_cookie() {
+        let other = Cookie {
+            seq: COOKIE.seq ^ 0x0101_0101,
+            ..COOKIE
+        };
+
+        for port in [53, 137, 11211] {
+            assert!(check_payload(port, COOKIE, &payload(port, COOKIE)));
+            assert!(!check_payload(port, other, &payload(port, COOKIE)));
+            assert!(!check_payload(port, COOKIE, &[0x43]));
+        }
+
+        // Servers move our transmit timestamp to the origin one, a reflected
+        // request doesn't pass
+        let mut ntp = [0u8; NTP_REQUEST_LEN];
+        ntp[0] = 0x24;
+        ntp[24..28].copy_from_slice(&COOKIE.seq.to_be_bytes());
+        assert!(check_payload(123, COOKIE, &ntp));
+        assert!(!check_payload(123, other, &ntp));
+        assert!(!check_payload(123, COOKIE, &payload(123, COOKIE)));
+
+        // Anything goes for services without an identifier
+        assert!(check_payload(1900, COOKIE, b"HTTP/1.1 200 OK\r\n"));
+    }
+
+    #[test]
+    fn snmp_responses_must_echo_the_request_id() {
+        let seq = COOKIE.seq.to_be_bytes();
+        assert!(check_payload(161, COOKIE, &snmp_response(&seq)));
+        assert!(!check_payload(161, COOKIE, &snmp_response(&[0x7f; 4])));
+        assert!(!check_payload(161, COOKIE, &payload(161, COOKIE)));
+        assert!(!check_payload(161, COOKIE, b"not snmp"));
+
+        // Ids are re-encoded in as few bytes as they take
+        let small = Cookie {
+            seq: 0x0000_1234,
+            ..COOKIE
+        };
+        assert!(check_payload(161, small, &snmp_response(&[0x12, 0x34])));
+        let positive = Cookie {
+            seq: 0x0080_0000,
+            ..COOKIE
+        };
+        assert!(check_payload(
+            161,
+            positive,
+            &snmp_response(&[0x00, 0x80, 0x00, 0x00])
+        ));
+
+        // Every truncation of a valid response is rejected without panicking
+        let response = snmp_response(&seq);
+        for len in 0..response.len() {
+            assert!(!check_payload(161, COOKIE, &response[..len]), "{}", len);
+        }
+    }
+}
@@ -0,0 +1,121 @@
+use std::hint::spin_loop;
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// Waits longer than this are slept, shorter ones are spun to keep the jitter low
+const SPIN_THRESHOLD: Duration = Duration::from_micros(200);
+
+/// Ethernet preamble, frame check sequence and inter-frame gap
+const FRAME_OVERHEAD: usize = 24;
+
+struct TokenBucket {
+    rate: f64,
+    burst: f64,
+    tokens: f64,
+    last: Instant,
+}
+
+impl TokenBucket {
+    fn new(rate: u64) -> Self {
+        let rate = rate as f64;
+        Self {
+            rate,
+            // Ten milliseconds worth of tokens, enough to absorb oversleeping
+            // and scheduling noise without allowing noticeable bursts
+            burst: rate / 100.0,
+            tokens: 0.0,
+            last: Instant::now(),
+        }
+    }
+
+    /// Time to wait before `cost` tokens are available, taking them if they already are
+    fn try_take(&mut self, cost: f64) -> Option<Duration> {
+        let now = Instant::now();
+        // Room for two packets so the time lost oversleeping is made up on the next one
+        let burst = self.burst.max(cost * 2.0);
+        self.tokens = (self.tokens + (now - self.last).as_secs_f64() * self.rate).min(burst);
+        self.last = now;
+
+        if self.tokens >= cost {
+            self.tokens -= cost;
+            return None;
+        }
+        Some(Duration::from_secs_f64((cost - self.tokens) / self.rate))
+    }
+}
+
+/// Paces the sender to a packet rate and/or a bandwidth
+pub struct RateLimiter {
+    packets: Option<TokenBucket>,
+    bits: Option<TokenBucket>,
+}
+
+impl RateLimiter {
+    /// `pps` in packets per second, `bps` in bits per second on the wire
+    pub fn new(pps: Option<u64>, bps: Option<u64>) -> Self {
+        Self {
+            packets: pps.map(TokenBucket::new),
+            bits: bps.map(TokenBucket::new),
+        }
+    }
+
+    /// Blocks until a frame of `len` bytes can be sent
+    pub fn acquire(&mut self, len: usize) {
+        if let Some(packets) = &mut self.packets {
+            Self::wait(packets, 1.0);
+        }
+        if let Some(bits) = &mut self.bits {
+            Self::wait(bits, ((len + FRAME_OVERHEAD) * 8) as f64);
+        }
+    }
+
+    fn wait(bucket: &mut TokenBucket, cost: f64) {
+        while let Some(wait) = bucket.try_take(cost) {
+            if wait > SPIN_THRESHOLD {
+                thread::sleep(wait - SPIN_THRESHOLD / 2);
+            } else {
+                spin_loop();
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn bucket_refills_at_rate_up_to_burst() {
+        let mut bucket = TokenBucket::new(1000);
+        let wait = bucket.try_take(1.0).unwrap();
+        assert!(wait <= Duration::from_millis(1), "{:?}", wait);
+
+        // A second of idling only leaves the burst, ten milliseconds of tokens
+        bucket.last -= Duration::from_secs(1);
+        for _ in 0..10 {
+            assert_eq!(bucket.try_take(1.0), None);
+        }
+        assert!(bucket.try_take(1.0).is_some());
+
+        // Frames costing more than the burst still go through, one at a time
+        let mut bits = TokenBucket::new(1000);
+        bits.last -= Duration::from_secs(1);
+        assert_eq!(bits.try_take(500.0), None);
+        assert!(bits.try_take(1000.0).is_some());
+    }
+
+    #[test]
+    fn limiter_paces_packets() {
+        let mut limiter = RateLimiter::new(Some(10_000), None);
+        let start = Instant::now();
+        for _ in 0..200 {
+            limiter.acquire(60);
+        }
+        // The bucket starts empty, so 200 packets take at least 20ms
+        assert!(
+            start.elapsed() >= Duration::from_millis(19),
+            "{:?}",
+            start.elapsed()
+        );
+    }
+}
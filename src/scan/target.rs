@@ -0,0 +1,269 @@
+use std::net::Ipv4Addr;
+use std::str::FromStr;
+
+/// Inclusive range of IPv4 addresses
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub struct IpRange {
+    pub start: u32,
+    pub end: u32,
+}
+
+impl IpRange {
+    pub fn size(&self) -> u64 {
+        (self.end - self.start) as u64 + 1
+    }
+
+    fn parse_ip(ip: &str) -> Result<u32, String> {
+        Ipv4Addr::from_str(ip.trim())
+            .map(|ip| ip.to_bits())
+            .map_err(|_| format!("Invalid IPv4 address: {}", ip))
+    }
+}
+
+impl FromStr for IpRange {
+    type Err = String;
+
+    /// Parses a CIDR block (`10.0.0.0/8`), a range (`10.0.0.1-10.0.3.255`) or a single address
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if let Some((ip, prefix)) = s.split_once('/') {
+            let ip = Self::parse_ip(ip)?;
+            let prefix = prefix
+                .trim()
+                .parse::<u32>()
+                .ok()
+                .filter(|prefix| *prefix <= 32)
+                .ok_or_else(|| format!("Invalid CIDR prefix length: {}", s))?;
+            let mask = u32::MAX.checked_shl(32 - prefix).unwrap_or(0);
+            return Ok(IpRange {
+                start: ip & mask,
+                end: ip | !mask,
+            });
+        }
+
+        if let Some((start, end)) = s.split_once('-') {
+            let start = Self::parse_ip(start)?;
+            let end = Self::parse_ip(end)?;
+            if start > end {
+                return Err(format!("Invalid range, start is after end: {}", s));
+            }
+            return Ok(IpRange { start, end });
+        }
+
+        let ip = Self::parse_ip(s)?;
+        Ok(IpRange { start: ip, end: ip })
+    }
+}
+
+/// Sorts the ranges and merges the overlapping or adjacent ones
+pub fn merge_ranges(mut ranges: Vec<IpRange>) -> Vec<IpRange> {
+    ranges.sort();
+
+    let mut merged: Vec<IpRange> = Vec::with_capacity(ranges.len());
+    for range in ranges {
+        match merged.last_mut() {
+            Some(last) if range.start as u64 <= last.end as u64 + 1 => {
+                last.end = last.end.max(range.end);
+            }
+            _ => merged.push(range),
+        }
+    }
+    merged
+}
+
+/// Union of the ranges to scan, addressable by index so it can be permuted
+#[derive(Clone, Debug)]
+pub struct TargetSet {
+    ranges: Vec<IpRange>,
+    offsets: Vec<u64>,
+    len: u64,
+}
+
+impl TargetSet {
+    pub fn new(ranges: Vec<IpRange>) -> Self {
+        let ranges = merge_ranges(ranges);
+
+        let mut offsets = Vec::with_capacity(ranges.len());
+        let mut len = 0;
+        for range in ranges.iter() {
+            offsets.push(len);
+            len += range.size();
+        }
+
+        Self {
+            ranges,
+            offsets,
+            len,
+        }
+    }
+
+    pub fn len(&self) -> u64 {
+        self.len
+    }
+
+    /// Sorted disjoint ranges making up the set
+    pub fn ranges(&self) -> &[IpRange] {
+        &self.ranges
+    }
+
+    /// Returns the address at `index` in the ordered union of ranges
+    pub fn get(&self, index: u64) -> Ipv4Addr {
+        let range_idx = self.offsets.partition_point(|offset| *offset <= index) - 1;
+        let range = &self.ranges[range_idx];
+        Ipv4Addr::from_bits(range.start + (index - self.offsets[range_idx]) as u32)
+    }
+
+    /// Index of `ip` in the ordered union of ranges, `None` if it is not a target
+    pub fn index_of(&self, ip: Ipv4Addr) -> Option<u64> {
+        let ip = ip.to_bits();
+        let range_idx = self.ranges.partition_point(|range| range.end < ip);
+        let range = self
+            .ranges
+            .get(range_idx)
+            .filter(|range| range.start <= ip)?;
+        Some(self.offsets[range_idx] + (ip - range.start) as u64)
+    }
+}
+
+impl Default for TargetSet {
+    fn default() -> Self {
+        Self::new(vec![IpRange {
+            start: 0,
+            end: u32::MAX,
+        }])
+    }
+}
+
+/// Inclusive range of ports, parsed from `80` or `8000-8100`
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct PortRange {
+    pub start: u16,
+    pub end: u16,
+}
+
+impl FromStr for PortRange {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let parse_port = |port: &str| {
+            port.trim()
+                .parse::<u16>()
+                .map_err(|_| format!("Invalid port: {}", port))
+        };
+
+        let (start, end) = match s.split_once('-') {
+            Some((start, end)) => (parse_port(start)?, parse_port(end)?),
+            None => (parse_port(s)?, parse_port(s)?),
+        };
+        if start > end {
+            return Err(format!("Invalid port range, start is after end: {}", s));
+        }
+        Ok(PortRange { start, end })
+    }
+}
+
+/// Flattens the port ranges into a sorted list of unique ports
+pub fn expand_ports(ranges: &[PortRange]) -> Vec<u16> {
+    let mut ports: Vec<u16> = ranges
+        .iter()
+        .flat_map(|range| range.start..=range.end)
+        .collect();
+    ports.sort_unstable();
+    ports.dedup();
+    ports
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn range(s: &str) -> IpRange {
+        IpRange::from_str(s).unwrap()
+    }
+
+    #[test]
+    fn parses_cidr_range_and_single_address() {
+        let cidr = range("10.1.2.3/16");
+        assert_eq!(Ipv4Addr::from_bits(cidr.start), Ipv4Addr::new(10, 1, 0, 0));
+        assert_eq!(Ipv4Addr::from_bits(cidr.end), Ipv4Addr::new(10, 1, 255, 255));
+        assert_eq!(range("0.0.0.0/0").size(), 1 << 32);
+        assert_eq!(range("1.2.3.4/32").size(), 1);
+
+        let span = range("10.0.0.250 - 10.0.1.5");
+        assert_eq!(span.size(), 12);
+
+        assert_eq!(range("8.8.8.8"), range("8.8.8.8/32"));
+    }
+
+    #[test]
+    fn rejects_invalid_ranges() {
+        for s in ["10.0.0.0/33", "10.0.0/8", "10.0.0.2-10.0.0.1", "nope", "1.2.3.4/x"] {
+            assert!(IpRange::from_str(s).is_err(), "{}", s);
+        }
+    }
+
+    #[test]
+    fn merges_overlapping_and_adjacent_ranges() {
+        let merged = merge_ranges(vec![
+            range("10.0.1.0/24"),
+            range("10.0.0.0/24"),
+            range("10.0.0.128/25"),
+            range("10.0.3.0/24"),
+        ]);
+        assert_eq!(merged, vec![range("10.0.0.0-10.0.1.255"), range("10.0.3.0/24")]);
+
+        let top = merge_ranges(vec![range("255.255.255.255"), range("255.255.255.254")]);
+        assert_eq!(top, vec![range("255.255.255.254/31")]);
+    }
+
+    #[test]
+    fn target_set_indexes_the_union_in_order() {
+        let targets = TargetSet::new(vec![
+            range("10.0.0.8/30"),
+            range("10.0.0.0/30"),
+            range("10.0.0.2"),
+        ]);
+        assert_eq!(targets.len(), 8);
+
+        let ips: Vec<Ipv4Addr> = (0..targets.len()).map(|i| targets.get(i)).collect();
+        let expected: Vec<Ipv4Addr> = [0, 1, 2, 3, 8, 9, 10, 11]
+            .iter()
+            .map(|last| Ipv4Addr::new(10, 0, 0, *last))
+            .collect();
+        assert_eq!(ips, expected);
+
+        for (i, ip) in ips.iter().enumerate() {
+            assert_eq!(targets.index_of(*ip), Some(i as u64));
+        }
+        assert_eq!(targets.index_of(Ipv4Addr::new(10, 0, 0, 5)), None);
+        assert_eq!(targets.index_of(Ipv4Addr::new(10, 0, 0, 12)), None);
+
+        let everything = TargetSet::default();
+        assert_eq!(everything.len(), 1 << 32);
+        assert_eq!(everything.get(u32::MAX as u64), Ipv4Addr::BROADCAST);
+    }
+
+    #[test]
+    fn parses_ports_and_port_ranges() {
+        assert_eq!(PortRange::from_str("80"), Ok(PortRange { start: 80, end: 80 }));
+        assert_eq!(
+            PortRange::from_str("8000 - 8100"),
+            Ok(PortRange {
+                start: 8000,
+                end: 8100
+            })
+        );
+        for s in ["65536", "90-80", "http", "1-"] {
+            assert!(PortRange::from_str(s).is_err(), "{}", s);
+        }
+    }
+
+    #[test]
+    fn expands_ports_sorted_and_unique() {
+        let ranges: Vec<PortRange> = ["443", "80-82", "81", "22"]
+            .iter()
+            .map(|s| PortRange::from_str(s).unwrap())
+            .collect();
+        assert_eq!(expand_ports(&ranges), vec![22, 80, 81, 82, 443]);
+        assert_eq!(expand_ports(&[PortRange { start: 0, end: 65535 }]).len(), 65536);
+    }
+}